@@ -0,0 +1,143 @@
+use crate::commands::utils::{self, parse_coordinate, read_text, uncompressed_name};
+
+/// A named interval from an annotation file, 1-based and inclusive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub contig: String,
+    pub start: u32,
+    pub end: u32,
+    pub name: String,
+}
+
+/// Read genes from a GFF3/GTF file, or every interval from a BED file.
+/// Either may be gzip or bgzip compressed
+pub fn read_annotation(path: &str) -> Result<Vec<Feature>, String> {
+    let contents =
+        read_text(path).map_err(|e| format!("Failed to read annotation file {}: {}", path, e))?;
+    let name = uncompressed_name(path);
+    let gff = [".gff", ".gff3", ".gtf"]
+        .iter()
+        .any(|ext| name.ends_with(ext));
+    let features = if gff {
+        parse_gff(&contents)
+    } else {
+        parse_bed(&contents)
+    };
+    features.map_err(|e| format!("Invalid annotation file {}: {}", path, e))
+}
+
+fn parse_gff(contents: &str) -> Result<Vec<Feature>, String> {
+    let mut features = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            return Err(format!("line {} has fewer than 9 columns", index + 1));
+        }
+        if fields[2] != "gene" {
+            continue;
+        }
+        features.push(Feature {
+            contig: fields[0].to_string(),
+            start: parse_coordinate(fields[3], index, "coordinate")?,
+            end: parse_coordinate(fields[4], index, "coordinate")?,
+            name: gff_name(fields[8]).unwrap_or_default(),
+        });
+    }
+    Ok(features)
+}
+
+/// The gene name from a GFF3 (`Name=`) or GTF (`gene_name "..."`) attribute column
+fn gff_name(attributes: &str) -> Option<String> {
+    let attributes: Vec<(&str, &str)> = attributes
+        .split(';')
+        .filter_map(|attribute| {
+            let attribute = attribute.trim();
+            attribute
+                .split_once('=')
+                .or_else(|| attribute.split_once(' '))
+        })
+        .collect();
+    ["Name", "gene_name", "gene", "gene_id", "ID"]
+        .iter()
+        .find_map(|key| {
+            attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.trim_matches('"').to_string())
+        })
+}
+
+fn parse_bed(contents: &str) -> Result<Vec<Feature>, String> {
+    Ok(utils::parse_bed(contents)?
+        .into_iter()
+        .map(|interval| Feature {
+            contig: interval.contig,
+            // BED starts are 0-based
+            start: interval.start + 1,
+            end: interval.end,
+            name: interval.name.unwrap_or_default(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gff() {
+        let gff = "##gff-version 3\n\
+                   chr1\tRefSeq\tregion\t1\t1000\t.\t+\t.\tID=chr1\n\
+                   chr1\tRefSeq\tgene\t10\t200\t.\t+\t.\tID=gene-S;Name=S\n\
+                   chr1\tRefSeq\tCDS\t10\t200\t.\t+\t0\tID=cds-S;gene=S\n\
+                   chr1\tensembl\tgene\t300\t400\t.\t-\t.\tgene_id \"g2\"; gene_name \"N\";\n";
+        let features = parse_gff(gff).unwrap();
+        assert_eq!(
+            features,
+            vec![
+                Feature {
+                    contig: "chr1".to_string(),
+                    start: 10,
+                    end: 200,
+                    name: "S".to_string()
+                },
+                Feature {
+                    contig: "chr1".to_string(),
+                    start: 300,
+                    end: 400,
+                    name: "N".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bed() {
+        let bed = "track name=genes\nchr1\t9\t200\tS\nchr2\t0\t50\n";
+        let features = parse_bed(bed).unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0].start, 10);
+        assert_eq!(features[0].name, "S");
+        assert_eq!(features[1].name, "");
+        assert!(parse_bed("chr1\tstart\t200\n").is_err());
+    }
+
+    #[test]
+    fn test_read_compressed_annotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("genes.gff3.gz");
+        let mut writer = rust_htslib::bgzf::Writer::from_path(&path).unwrap();
+        std::io::Write::write_all(
+            &mut writer,
+            b"chr1\tRefSeq\tgene\t10\t200\t.\t+\t.\tID=gene-S;Name=S\n",
+        )
+        .unwrap();
+        drop(writer);
+        let features = read_annotation(path.to_str().unwrap()).unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].name, "S");
+    }
+}
@@ -1,6 +1,15 @@
+use std::fmt;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 
-use clap::{Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+
+use crate::config::{
+    Config, DepthMode, HomopolymerAlleles, MaskMode, MateConflict, PlotType, PlotValues, Preset,
+    Settings, ThresholdMode, Weighting, DEFAULT_MAX_DEPTH,
+};
+use crate::style::{read_colours, LegendPosition, Palette, PlotStyle};
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Visulise ambigous bases in a bam file.")]
@@ -21,9 +30,27 @@ pub enum Command {
         #[arg(required = false)]
         region: Option<String>,
 
-        // Output
-        #[arg(short = 'o', long = "output", default_value = "ambig")]
-        output: String,
+        // Output prefix, defaults to the input file name
+        #[arg(short = 'o', long = "output")]
+        output: Option<String>,
+
+        #[command(flatten)]
+        outputs: OutputArgs,
+
+        #[command(flatten)]
+        style: StyleArgs,
+
+        // Output results as JSON
+        #[arg(long = "json")]
+        json: bool,
+
+        // Reads held in a pileup column before htslib drops new ones, 0 for no limit
+        #[arg(long = "max-depth", default_value_t = DEFAULT_MAX_DEPTH)]
+        max_depth: u32,
+
+        // Count each fragment once where the mates of a pair overlap
+        #[arg(long = "dedup-overlaps")]
+        dedup_overlaps: bool,
     },
     /// Plot Ambigous bases
     Ambig {
@@ -35,56 +62,416 @@ pub enum Command {
         #[arg(required = false)]
         region: Option<String>,
 
-        // Output
-        #[arg(short = 'o', long = "output", default_value = "ambig")]
+        // Output prefix, defaults to the input file name
+        #[arg(short = 'o', long = "output")]
+        output: Option<String>,
+
+        #[command(flatten)]
+        outputs: OutputArgs,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+
+        #[command(flatten)]
+        style: StyleArgs,
+
+        // Do not include labels
+        #[arg(long = "no-label", overrides_with = "label")]
+        no_label: bool,
+
+        // Include labels, overriding no_label from the config file
+        #[arg(long = "label", overrides_with = "no_label")]
+        label: bool,
+
+        // Output bed file instead of plot
+        #[arg(long = "bed", overrides_with = "no_bed")]
+        bed: bool,
+
+        // Do not output a bed file, overriding the config file
+        #[arg(long = "no-bed", overrides_with = "bed")]
+        no_bed: bool,
+
+        // Output results as JSON
+        #[arg(long = "json", overrides_with = "no_json")]
+        json: bool,
+
+        // Do not output JSON, overriding the config file
+        #[arg(long = "no-json", overrides_with = "json")]
+        no_json: bool,
+
+        // Report every ambiguous candidate, including those that failed a filter, and why
+        #[arg(long = "report-all", overrides_with = "no_report_all")]
+        report_all: bool,
+
+        // Only report candidates that passed, overriding the config file
+        #[arg(long = "no-report-all", overrides_with = "report_all")]
+        no_report_all: bool,
+
+        // Plots to draw for each contig, comma separated
+        #[arg(long = "plot-type", value_delimiter = ',')]
+        plot_types: Option<Vec<PlotType>>,
+
+        // Plot the proportion or number of reads supporting each allele
+        #[arg(long = "plot-values")]
+        plot_values: Option<PlotValues>,
+
+        // Split the bar and strand plots into pages of at most this many positions, 0 for one plot
+        #[arg(long = "max-positions-per-plot")]
+        max_positions_per_plot: Option<usize>,
+
+        // GFF3, GTF or BED file of genes to draw on the overview plot
+        #[arg(long = "annotation", value_parser(check_input_exists))]
+        annotation: Option<String>,
+
+        // VCF or TSV file of positions to report allele counts at, ambiguous or not
+        #[arg(long = "sites", value_parser(check_input_exists))]
+        sites: Option<String>,
+    },
+    /// Write a single HTML report of depth and ambiguous bases
+    Report {
+        // Path to input bam
+        #[arg(required = true, value_parser(check_input_exists))]
+        input: String,
+
+        // SAMtools region string
+        #[arg(required = false)]
+        region: Option<String>,
+
+        // Output prefix, defaults to the input file name
+        #[arg(short = 'o', long = "output")]
+        output: Option<String>,
+
+        #[command(flatten)]
+        outputs: OutputArgs,
+
+        #[command(flatten)]
+        filters: FilterArgs,
+
+        #[command(flatten)]
+        style: StyleArgs,
+
+        // Also list ambiguous candidates that failed a filter, and why
+        #[arg(long = "report-all", overrides_with = "no_report_all")]
+        report_all: bool,
+
+        // Only list candidates that passed, overriding the config file
+        #[arg(long = "no-report-all", overrides_with = "report_all")]
+        no_report_all: bool,
+    },
+    /// Compare minor allele frequencies between samples
+    Compare {
+        // Paths to input bams, in timepoint order
+        #[arg(required = true, num_args = 2.., value_parser(check_input_exists))]
+        inputs: Vec<String>,
+
+        // SAMtools region string
+        #[arg(short = 'r', long = "region")]
+        region: Option<String>,
+
+        // Sample labels, in the same order as the inputs
+        #[arg(short = 'l', long = "labels", value_delimiter = ',')]
+        labels: Option<Vec<String>>,
+
+        // Output prefix
+        #[arg(short = 'o', long = "output", default_value = "compare")]
         output: String,
 
-        // Threshold for ambigous bases
+        #[command(flatten)]
+        outputs: OutputArgs,
+
+        // Minimum change in minor allele frequency to report
         #[arg(
-            short = 't',
-            long = "threshold",
-            default_value = "0.1",
-            value_parser(check_threshold_valid)
+            short = 'c',
+            long = "change-threshold",
+            default_value = "0.2",
+            value_parser(check_change_threshold_valid)
         )]
-        threshold: f64,
+        change_threshold: f64,
 
-        // Threshold for base quality
-        #[arg(short = 'q', long = "--min-BQ", default_value = "20")]
-        base_quality_threshold: u8,
+        #[command(flatten)]
+        filters: FilterArgs,
+    },
+}
 
-        // Threshold for map quality
-        #[arg(short = 'Q', long = "--min-MQ", default_value = "60")]
-        map_quality_threshold: u8,
+/// Where output files are written, shared by every command
+#[derive(Args, Debug, Clone)]
+pub struct OutputArgs {
+    // Directory to write outputs to, created if it does not exist
+    #[arg(long = "outdir", default_value = ".")]
+    pub outdir: String,
 
-        // Threshold for total depth
-        #[arg(short = 'd', long = "--depth", default_value = "100")]
-        depth_threshold: u32,
+    // Overwrite existing output files
+    #[arg(long = "force")]
+    pub force: bool,
+}
 
-        // Threshold for depth of minor allele
-        // #[arg(short = 'd', long = "--minor-depth", default_value = "20")]
-        // minor_depth_threshold: u32,
+/// Plot presentation options shared by every command that draws ambig or depth plots
+#[derive(Args, Debug, Clone)]
+pub struct StyleArgs {
+    // Plot title template, {plot}, {sample}, {contig} and {region} are filled in
+    #[arg(long = "title")]
+    pub title: Option<String>,
 
-        // Threshold for strand bias
-        #[arg(
-            short = 's',
-            long = "--strand-bias",
-            default_value = "0.1",
-            value_parser(check_threshold_valid)
-        )]
-        strand_bias_threshold: f64,
+    // Named colour palette [default: default]
+    #[arg(long = "palette")]
+    pub palette: Option<Palette>,
 
-        // Do not include indels
-        #[arg(long = "no-indel")]
-        no_indel: bool,
+    // TOML file of allele and depth colours, used in place of the palette
+    #[arg(long = "colours", value_parser(check_input_exists))]
+    pub colours: Option<String>,
 
-        // Do not include labels
-        #[arg(long = "no-label")]
-        no_label: bool,
+    // Font size of plot text
+    #[arg(long = "font-size")]
+    pub font_size: Option<usize>,
 
-        // Output bed file instead of plot
-        #[arg(long = "bed")]
-        bed: bool,
-    },
+    // Where to place the plot legend [default: right]
+    #[arg(long = "legend")]
+    pub legend: Option<LegendPosition>,
+}
+
+impl StyleArgs {
+    /// The plot style from the defaults and these flags, for commands without a config file
+    pub fn resolve(&self) -> Result<PlotStyle, String> {
+        let mut style = PlotStyle::default();
+        self.apply(&mut style)?;
+        Ok(style)
+    }
+
+    /// Override a plot style from the defaults or a config file with any flags given
+    pub fn apply(&self, style: &mut PlotStyle) -> Result<(), String> {
+        if let Some(title) = &self.title {
+            style.title = Some(title.clone());
+        }
+        if let Some(palette) = self.palette {
+            style.palette = palette;
+        }
+        if let Some(path) = &self.colours {
+            style.colours = read_colours(path)?;
+        }
+        if let Some(font_size) = self.font_size {
+            style.font_size = Some(font_size);
+        }
+        if let Some(legend) = self.legend {
+            style.legend = legend;
+        }
+        Ok(())
+    }
+}
+
+/// Read and pileup filters shared by every command that scans for ambiguous bases
+#[derive(Args, Debug, Clone)]
+pub struct FilterArgs {
+    // TOML file of settings, overridden by any flags given on the command line
+    #[arg(long = "config", value_parser(check_input_exists))]
+    pub config: Option<String>,
+
+    // Named set of thresholds, overridden by the config file and command line
+    #[arg(long = "preset", value_enum)]
+    pub preset: Option<Preset>,
+
+    // Threshold for ambigous bases [default: 0.1]
+    #[arg(short = 't', long = "threshold", value_parser(check_threshold_valid))]
+    pub threshold: Option<f64>,
+
+    // Compare the threshold to the summed minor alleles (total) or to each minor allele (per-allele) [default: total]
+    #[arg(long = "threshold-mode")]
+    pub threshold_mode: Option<ThresholdMode>,
+
+    // Lowest frequency of a non-reference allele for a position to pass [default: 0]
+    #[arg(long = "min-af", value_parser(check_frequency_valid))]
+    pub min_af: Option<f64>,
+
+    // Highest frequency of a non-reference allele for a position to pass [default: 1]
+    #[arg(long = "max-af", value_parser(check_frequency_valid))]
+    pub max_af: Option<f64>,
+
+    // Threshold for base quality [default: 20]
+    #[arg(short = 'q', long = "min-BQ")]
+    pub base_quality_threshold: Option<u8>,
+
+    // Threshold for map quality [default: 60]
+    #[arg(short = 'Q', long = "min-MQ")]
+    pub map_quality_threshold: Option<u8>,
+
+    // Threshold for total depth [default: 100]
+    #[arg(short = 'd', long = "depth")]
+    pub depth_threshold: Option<u32>,
+
+    // Compare the depth threshold to the raw pileup depth or the reads left after read filtering [default: raw]
+    #[arg(long = "depth-mode")]
+    pub depth_mode: Option<DepthMode>,
+
+    // Reads held in a pileup column before htslib drops new ones, 0 for no limit [default: 8000]
+    #[arg(long = "max-depth")]
+    pub max_depth: Option<u32>,
+
+    // Count one base per fragment where the mates of a pair overlap
+    #[arg(long = "dedup-overlaps", overrides_with = "no_dedup_overlaps")]
+    pub dedup_overlaps: bool,
+
+    // Count both mates where they overlap, overriding the preset or config file
+    #[arg(long = "no-dedup-overlaps", overrides_with = "dedup_overlaps")]
+    pub no_dedup_overlaps: bool,
+
+    // Mates that disagree with --dedup-overlaps, keep the higher quality base (best) or neither (discard) [default: best]
+    #[arg(long = "mate-conflict", requires = "dedup_overlaps")]
+    pub mate_conflict: Option<MateConflict>,
+
+    // Recalculate base qualities with BAQ before the base quality filter, needs --reference
+    #[arg(long = "baq", overrides_with = "no_baq", requires = "reference")]
+    pub baq: bool,
+
+    // Use the base qualities as read, overriding the preset or config file
+    #[arg(long = "no-baq", overrides_with = "baq")]
+    pub no_baq: bool,
+
+    // Threshold for depth of minor allele
+    // #[arg(short = 'd', long = "--minor-depth", default_value = "20")]
+    // minor_depth_threshold: u32,
+
+    // Threshold for strand bias [default: 0.1]
+    #[arg(short = 's', long = "strand-bias", value_parser(check_threshold_valid))]
+    pub strand_bias_threshold: Option<f64>,
+
+    // Do not include indels
+    #[arg(long = "no-indel", overrides_with = "indel")]
+    pub no_indel: bool,
+
+    // Include indels, overriding the preset or config file
+    #[arg(long = "indel", overrides_with = "no_indel")]
+    pub indel: bool,
+
+    // Weight each read by its base quality (bq), or base and mapping quality (bq-mq) [default: none]
+    #[arg(long = "weighting")]
+    pub weighting: Option<Weighting>,
+
+    // Remove bases within this many aligned bases of either end of a read, soft clips are not counted [default: 0]
+    #[arg(long = "read-end-distance")]
+    pub read_end_distance: Option<u32>,
+
+    // FASTA reference used to report the sequence context of each position
+    #[arg(long = "reference", value_parser(check_input_exists))]
+    pub reference: Option<String>,
+
+    // Remove alleles inside homopolymers longer than this, needs --reference [default: 0]
+    #[arg(long = "max-homopolymer", requires = "reference")]
+    pub max_homopolymer: Option<u32>,
+
+    // Alleles removed by --max-homopolymer, indels or all [default: indels]
+    #[arg(long = "homopolymer-alleles")]
+    pub homopolymer_alleles: Option<HomopolymerAlleles>,
+
+    // BED or VCF file of positions to leave out of the scan
+    #[arg(long = "mask", value_parser(check_input_exists))]
+    pub mask: Option<String>,
+
+    // Exclude masked positions, or flag them as masked in the outputs [default: exclude]
+    #[arg(long = "mask-mode")]
+    pub mask_mode: Option<MaskMode>,
+}
+
+impl FilterArgs {
+    /// Resolve the effective settings: defaults, then preset, then config file, then flags
+    pub fn resolve(&self) -> Result<Settings, String> {
+        let mut settings = match self.preset {
+            Some(preset) => Settings::from_preset(preset),
+            None => Settings::default(),
+        };
+        // the mate conflict mode only applies when overlapping mates are grouped
+        let mut mate_conflict_set = self.mate_conflict.is_some();
+        if let Some(path) = &self.config {
+            let config = Config::from_path(path)?;
+            settings.apply_config(&config);
+            mate_conflict_set |= config.mate_conflict.is_some();
+            if let Some(colours) = &config.colours {
+                settings.style.colours = read_colours(colours)?;
+            }
+        }
+        if let Some(threshold) = self.threshold {
+            settings.threshold = threshold;
+        }
+        if let Some(threshold_mode) = self.threshold_mode {
+            settings.threshold_mode = threshold_mode;
+        }
+        if let Some(min_af) = self.min_af {
+            settings.min_af = min_af;
+        }
+        if let Some(max_af) = self.max_af {
+            settings.max_af = max_af;
+        }
+        if let Some(base_quality_threshold) = self.base_quality_threshold {
+            settings.base_quality_threshold = base_quality_threshold;
+        }
+        if let Some(map_quality_threshold) = self.map_quality_threshold {
+            settings.map_quality_threshold = map_quality_threshold;
+        }
+        if let Some(depth_threshold) = self.depth_threshold {
+            settings.depth_threshold = depth_threshold;
+        }
+        if let Some(depth_mode) = self.depth_mode {
+            settings.depth_mode = depth_mode;
+        }
+        if let Some(max_depth) = self.max_depth {
+            settings.max_depth = max_depth;
+        }
+        apply_flag(
+            &mut settings.dedup_overlaps,
+            self.dedup_overlaps,
+            self.no_dedup_overlaps,
+        );
+        if let Some(mate_conflict) = self.mate_conflict {
+            settings.mate_conflict = mate_conflict;
+        }
+        apply_flag(&mut settings.baq, self.baq, self.no_baq);
+        if let Some(strand_bias_threshold) = self.strand_bias_threshold {
+            settings.strand_bias_threshold = strand_bias_threshold;
+        }
+        apply_flag(&mut settings.no_indel, self.no_indel, self.indel);
+        if let Some(weighting) = self.weighting {
+            settings.weighting = weighting;
+        }
+        if let Some(read_end_distance) = self.read_end_distance {
+            settings.read_end_distance = read_end_distance;
+        }
+        if let Some(reference) = &self.reference {
+            settings.reference = Some(reference.clone());
+        }
+        if let Some(max_homopolymer) = self.max_homopolymer {
+            settings.max_homopolymer = max_homopolymer;
+        }
+        if let Some(homopolymer_alleles) = self.homopolymer_alleles {
+            settings.homopolymer_alleles = homopolymer_alleles;
+        }
+        if let Some(mask) = &self.mask {
+            settings.mask = Some(mask.clone());
+        }
+        if let Some(mask_mode) = self.mask_mode {
+            settings.mask_mode = mask_mode;
+        }
+        settings.validate()?;
+        if mate_conflict_set && !settings.dedup_overlaps {
+            return Err(
+                "mate_conflict needs dedup_overlaps to group the mates of each pair".to_string(),
+            );
+        }
+        Ok(settings)
+    }
+}
+
+/// Apply an on/off flag pair over a setting from the preset or config, the last flag given wins
+pub fn apply_flag(setting: &mut bool, on: bool, off: bool) {
+    if on {
+        *setting = true;
+    } else if off {
+        *setting = false;
+    }
+}
+
+/// Print an error with the usage line and exit, as clap does for an invalid argument
+pub fn exit_with_error(message: impl fmt::Display) -> ! {
+    Cli::command()
+        .error(ErrorKind::InvalidValue, message)
+        .exit()
 }
 
 fn check_input_exists(s: &str) -> Result<String, String> {
@@ -95,15 +482,33 @@ fn check_input_exists(s: &str) -> Result<String, String> {
     }
 }
 
-fn check_threshold_valid(s: &str) -> Result<f64, String> {
-    let threshold = f64::from_str(s).unwrap();
-    if threshold >= 0.0 && threshold <= 0.5 {
-        Ok(threshold)
+/// Parse a number that must lie within `range`, naming the value in the error
+fn parse_in_range(s: &str, range: RangeInclusive<f64>, name: &str) -> Result<f64, String> {
+    let value = f64::from_str(s).map_err(|_| format!("{} must be a number, found {}", name, s))?;
+    if range.contains(&value) {
+        Ok(value)
     } else {
-        Err(format!("Threshold must be between 0 and 0.5"))
+        Err(format!(
+            "{} must be between {} and {}",
+            name,
+            range.start(),
+            range.end()
+        ))
     }
 }
 
+fn check_threshold_valid(s: &str) -> Result<f64, String> {
+    parse_in_range(s, 0.0..=0.5, "Threshold")
+}
+
+fn check_frequency_valid(s: &str) -> Result<f64, String> {
+    parse_in_range(s, 0.0..=1.0, "Allele frequency")
+}
+
+fn check_change_threshold_valid(s: &str) -> Result<f64, String> {
+    parse_in_range(s, 0.0..=1.0, "Change threshold")
+}
+
 pub fn parse_region(region: &str) -> (&str, u32, Option<u32>) {
     // Check if we have just chromosome or both chromosome and region
     if region.contains(':') {
@@ -166,4 +571,146 @@ mod tests {
         assert_eq!(start, 200);
         assert_eq!(end, Some(300));
     }
+
+    #[test]
+    fn test_threshold_validation() {
+        assert_eq!(check_threshold_valid("0.2"), Ok(0.2));
+        assert_eq!(
+            check_threshold_valid("abc"),
+            Err("Threshold must be a number, found abc".to_string())
+        );
+        assert_eq!(
+            check_frequency_valid("abc"),
+            Err("Allele frequency must be a number, found abc".to_string())
+        );
+        assert_eq!(
+            check_change_threshold_valid("1.5"),
+            Err("Change threshold must be between 0 and 1".to_string())
+        );
+        assert!(
+            Cli::try_parse_from(["ambigviz", "ambig", "test-data/ambig.bam", "-t", "abc"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolve_settings_precedence() {
+        let cli = Cli::parse_from([
+            "ambigviz",
+            "ambig",
+            "test-data/ambig.bam",
+            "--preset",
+            "ont",
+            "-q",
+            "15",
+        ]);
+        let Command::Ambig { filters, .. } = cli.command else {
+            panic!("Expected ambig command");
+        };
+        let settings = filters.resolve().unwrap();
+        assert_eq!(settings.base_quality_threshold, 15);
+        assert_eq!(settings.threshold, 0.2);
+        assert!(settings.no_indel);
+    }
+
+    #[test]
+    fn test_resolve_flags_override_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        std::fs::write(
+            &config,
+            "no_indel = true\ndedup_overlaps = true\nbaq = false\n",
+        )
+        .unwrap();
+        let cli = Cli::parse_from([
+            "ambigviz",
+            "ambig",
+            "test-data/ambig.bam",
+            "--config",
+            config.to_str().unwrap(),
+            "--indel",
+            "--no-dedup-overlaps",
+            "--no-baq",
+            "--baq",
+            "--reference",
+            "test-data/ambig.fa",
+        ]);
+        let Command::Ambig { filters, .. } = cli.command else {
+            panic!("Expected ambig command");
+        };
+        let settings = filters.resolve().unwrap();
+        assert!(!settings.no_indel);
+        assert!(!settings.dedup_overlaps);
+        assert!(settings.baq);
+    }
+
+    #[test]
+    fn test_resolve_style_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        std::fs::write(&config, "title = \"{sample}\"\nlegend = \"top\"\n").unwrap();
+        let cli = Cli::parse_from([
+            "ambigviz",
+            "ambig",
+            "test-data/ambig.bam",
+            "--config",
+            config.to_str().unwrap(),
+            "--legend",
+            "bottom",
+        ]);
+        let Command::Ambig { filters, style, .. } = cli.command else {
+            panic!("Expected ambig command");
+        };
+        let mut settings = filters.resolve().unwrap();
+        style.apply(&mut settings.style).unwrap();
+        assert_eq!(settings.style.title, Some("{sample}".to_string()));
+        assert_eq!(settings.style.legend, LegendPosition::Bottom);
+
+        std::fs::write(&config, "threshold = 0.9\n").unwrap();
+        assert!(filters.resolve().is_err());
+    }
+
+    #[test]
+    fn test_dependent_options() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["ambigviz", "ambig", "test-data/ambig.bam"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        assert!(parse(&["--max-homopolymer", "5"]).is_err());
+        assert!(parse(&[
+            "--max-homopolymer",
+            "5",
+            "--reference",
+            "test-data/ambig.fa"
+        ])
+        .is_ok());
+
+        // the config file can set one option without the other
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        let resolve = |contents: &str| {
+            std::fs::write(&config, contents).unwrap();
+            let Command::Ambig { filters, .. } = parse(&["--config", config.to_str().unwrap()])
+                .unwrap()
+                .command
+            else {
+                panic!("Expected ambig command");
+            };
+            filters.resolve()
+        };
+        assert!(resolve("max_homopolymer = 5\n").is_err());
+        assert!(resolve("max_homopolymer = 5\nreference = \"test-data/ambig.fa\"\n").is_ok());
+        assert!(resolve("min_af = 0.5\nmax_af = 0.4\n").is_err());
+        assert!(resolve("baq = true\n").is_err());
+        assert!(resolve("mate_conflict = \"best\"\n").is_err());
+        assert!(resolve("mate_conflict = \"discard\"\ndedup_overlaps = true\n").is_ok());
+        assert!(parse(&["--mate-conflict", "best"]).is_err());
+        assert!(parse(&["--mate-conflict", "discard", "--dedup-overlaps"]).is_ok());
+        assert!(parse(&["--baq"]).is_err());
+        assert!(
+            Cli::try_parse_from(["ambigviz", "depth", "test-data/ambig.bam", "--baq"]).is_err()
+        );
+    }
 }
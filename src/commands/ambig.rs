@@ -1,28 +1,58 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
 
+#[cfg(feature = "plot")]
 use plotly::{
-    common::{Marker, TextAnchor, TextPosition, Title},
+    common::{HoverInfo, Line, Marker, Mode, TextAnchor, TextPosition, Title},
     layout::{Axis, AxisType::Category, BarMode},
-    Bar, ImageFormat,
+    Bar, Scatter,
 };
-use rust_htslib::{bam, bam::Read};
+use rust_htslib::{bam, bam::record::Cigar, bam::Read};
+use serde::Serialize;
 
-use crate::commands::utils::{get_start_stop, get_tids, read_bam};
+#[cfg(feature = "plot")]
+use crate::annotation::{read_annotation, Feature};
+use crate::commands::utils::{
+    get_start_stop, get_tids, pileup_columns, plot_outputs, plot_supported, read_bam, sample_name,
+    OutputPaths,
+};
+#[cfg(feature = "plot")]
+use crate::commands::utils::{write_plot, PLOT_EXT};
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+use crate::config::PlotValues;
+use crate::config::{
+    DepthMode, HomopolymerAlleles, MaskMode, MateConflict, PlotType, RunSettings, Settings,
+    ThresholdMode, Weighting,
+};
+use crate::json::AmbigJson;
+use crate::mask::Mask;
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+use crate::provenance::format_region;
+use crate::provenance::Provenance;
+use crate::reference::{Reference, SequenceContext};
+#[cfg(feature = "native-plot")]
+use crate::render::{self, BarPosition, BarSeries, Titles};
+use crate::sites::{read_sites, Site};
 
-struct Pileup {
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Pileup {
     // (total, (forward, reverse))
-    a: (u32, (u32, u32)),
-    t: (u32, (u32, u32)),
-    c: (u32, (u32, u32)),
-    g: (u32, (u32, u32)),
-    del: (u32, (u32, u32)),
-    ins: (u32, (u32, u32)),
+    pub(crate) a: (u32, (u32, u32)),
+    pub(crate) t: (u32, (u32, u32)),
+    pub(crate) c: (u32, (u32, u32)),
+    pub(crate) g: (u32, (u32, u32)),
+    pub(crate) del: (u32, (u32, u32)),
+    pub(crate) ins: (u32, (u32, u32)),
+    // summed read weights of each allele, empty unless --weighting is set
+    pub(crate) weighted: BTreeMap<char, f64>,
+    // distance of the allele from the nearer aligned end of each supporting read
+    pub(crate) end_distances: BTreeMap<char, Vec<u32>>,
 }
 
 impl Pileup {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             a: (0, (0, 0)),
             t: (0, (0, 0)),
@@ -30,31 +60,52 @@ impl Pileup {
             g: (0, (0, 0)),
             del: (0, (0, 0)),
             ins: (0, (0, 0)),
+            weighted: BTreeMap::new(),
+            end_distances: BTreeMap::new(),
         }
     }
 
-    fn get_total(&self) -> u32 {
+    pub(crate) fn get_total(&self) -> u32 {
         self.a.0 + self.t.0 + self.c.0 + self.g.0 + self.del.0 + self.ins.0
     }
 
-    fn get_major_variant(&self) -> char {
-        let (major_variant, _) = [
-            ('A', self.a.0),
-            ('T', self.t.0),
-            ('C', self.c.0),
-            ('G', self.g.0),
-            ('-', self.del.0),
-            ('+', self.ins.0),
-        ]
-        .iter()
-        .cloned() // Clone the values to avoid borrowing issues
-        .max_by_key(|&(_, i)| i)
-        .expect("No bases found in base_counts.");
+    /// The allele with the most support, by weight when the reads were weighted
+    pub(crate) fn get_major_variant(&self) -> char {
+        let (major_variant, _) = ['A', 'T', 'C', 'G', '-', '+']
+            .iter()
+            .map(|base| (*base, self.get_weight(*base)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("No bases found in base_counts.");
         major_variant
     }
 
+    pub(crate) fn is_weighted(&self) -> bool {
+        !self.weighted.is_empty()
+    }
+
+    /// Summed read weights supporting `base`, or the read count if the reads were not weighted
+    pub(crate) fn get_weight(&self, base: char) -> f64 {
+        if self.is_weighted() {
+            self.weighted.get(&base).cloned().unwrap_or(0.0)
+        } else {
+            self.get_base_count(base) as f64
+        }
+    }
+
+    pub(crate) fn get_total_weight(&self) -> f64 {
+        ['A', 'C', 'G', 'T', '-', '+']
+            .iter()
+            .map(|base| self.get_weight(*base))
+            .fold(0.0, |sum, weight| sum + weight)
+    }
+
+    /// Proportion of the pileup supporting `base`, from the weights if the reads were weighted
+    pub(crate) fn get_proportion(&self, base: char) -> f64 {
+        self.get_weight(base) / self.get_total_weight()
+    }
+
     fn get_strand_ratio(&self, base: char) -> f64 {
-        let (total, (forward, reverse)) = match base {
+        let (total, (forward, _)) = match base {
             'A' => self.a,
             'T' => self.t,
             'C' => self.c,
@@ -66,12 +117,10 @@ impl Pileup {
         if total == 0 {
             return -1.0;
         }
-        let forward_ratio = forward as f64 / total as f64;
-        //let reverse_ratio = reverse as f64 / total as f64;
-        forward_ratio
+        forward as f64 / total as f64
     }
 
-    fn get_base_count(&self, base: char) -> u32 {
+    pub(crate) fn get_base_count(&self, base: char) -> u32 {
         match base {
             'A' => self.a.0,
             'T' => self.t.0,
@@ -83,6 +132,69 @@ impl Pileup {
         }
     }
 
+    /// Count one read supporting `base` on the given strand
+    pub(crate) fn add(&mut self, base: char, forward: bool) {
+        let counts = match base {
+            'A' => &mut self.a,
+            'T' => &mut self.t,
+            'C' => &mut self.c,
+            'G' => &mut self.g,
+            '-' => &mut self.del,
+            '+' => &mut self.ins,
+            _ => panic!("Invalid base"),
+        };
+        counts.0 += 1;
+        if forward {
+            counts.1 .0 += 1;
+        } else {
+            counts.1 .1 += 1;
+        }
+    }
+
+    /// Count one read supporting `base` that contributes `weight` to the weighted counts
+    pub(crate) fn add_weighted(&mut self, base: char, forward: bool, weight: f64) {
+        self.add(base, forward);
+        *self.weighted.entry(base).or_insert(0.0) += weight;
+    }
+
+    /// Record how far from the nearer end of its read a supporting base was
+    pub(crate) fn add_end_distance(&mut self, base: char, distance: u32) {
+        self.end_distances.entry(base).or_default().push(distance);
+    }
+
+    /// Mean distance of `base` from the nearer end of the reads supporting it
+    pub(crate) fn mean_end_distance(&self, base: char) -> Option<f64> {
+        let distances = self.end_distances.get(&base)?;
+        if distances.is_empty() {
+            return None;
+        }
+        Some(distances.iter().map(|d| *d as f64).sum::<f64>() / distances.len() as f64)
+    }
+
+    /// Rank sum z-score of the read end distances of `base` against those of `major`, negative
+    /// when `base` sits nearer the read ends than the major allele
+    pub(crate) fn read_pos_rank_sum(&self, base: char, major: char) -> Option<f64> {
+        if base == major {
+            return None;
+        }
+        rank_sum_z(
+            self.end_distances.get(&base)?,
+            self.end_distances.get(&major)?,
+        )
+    }
+
+    pub(crate) fn get_strand_counts(&self, base: char) -> (u32, u32) {
+        match base {
+            'A' => self.a.1,
+            'T' => self.t.1,
+            'C' => self.c.1,
+            'G' => self.g.1,
+            '-' => self.del.1,
+            '+' => self.ins.1,
+            _ => panic!("Invalid base"),
+        }
+    }
+
     fn is_ambiguous(&self) -> bool {
         let mut count = 0;
         if self.a.0 > 0 {
@@ -110,20 +222,427 @@ impl Pileup {
     }
 }
 
+/// Read level filters that can exclude a read from a pileup column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ReadFilter {
+    // secondary alignments can have no stored sequence
+    EmptySeq,
+    BaseQuality,
+    MapQuality,
+    // indels excluded by --no-indel
+    Indel,
+    // bases within --read-end-distance of either end of the aligned read
+    ReadEnd,
+    // alleles inside a homopolymer longer than --max-homopolymer
+    Homopolymer,
+    // the mate of a pair with the lower base quality where both cover the column
+    Overlap,
+}
+
+/// Number of reads removed by each read level filter
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct ReadFilterCounts {
+    pub(crate) empty_seq: u32,
+    pub(crate) base_quality: u32,
+    pub(crate) map_quality: u32,
+    pub(crate) indel: u32,
+    pub(crate) read_end: u32,
+    pub(crate) homopolymer: u32,
+    pub(crate) overlap: u32,
+}
+
+impl ReadFilterCounts {
+    fn add(&mut self, filter: ReadFilter) {
+        match filter {
+            ReadFilter::EmptySeq => self.empty_seq += 1,
+            ReadFilter::BaseQuality => self.base_quality += 1,
+            ReadFilter::MapQuality => self.map_quality += 1,
+            ReadFilter::Indel => self.indel += 1,
+            ReadFilter::ReadEnd => self.read_end += 1,
+            ReadFilter::Homopolymer => self.homopolymer += 1,
+            ReadFilter::Overlap => self.overlap += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &ReadFilterCounts) {
+        self.empty_seq += other.empty_seq;
+        self.base_quality += other.base_quality;
+        self.map_quality += other.map_quality;
+        self.indel += other.indel;
+        self.read_end += other.read_end;
+        self.homopolymer += other.homopolymer;
+        self.overlap += other.overlap;
+    }
+}
+
+/// Allele counts at a single pileup column before and after read filtering
+pub(crate) struct ColumnCounts {
+    pub(crate) pileup: Pileup,
+    pub(crate) unfiltered: Pileup,
+    pub(crate) removed: ReadFilterCounts,
+    // reads that contributed at least one allele to `pileup`
+    pub(crate) reads: u32,
+    // pairs with both mates covering the column, and those whose mates disagree, only counted
+    // with --dedup-overlaps
+    pub(crate) overlapping_pairs: u32,
+    pub(crate) mate_conflicts: u32,
+    // reference sequence around the column, None without --reference
+    pub(crate) context: Option<SequenceContext>,
+}
+
+/// A read at a pileup column that passed the read filters, with the alleles it still supports
+struct ColumnRead {
+    // read name of a paired read, to find its mate
+    name: Option<Vec<u8>>,
+    forward: bool,
+    quality: Option<u8>,
+    alleles: Vec<char>,
+    end_distance: u32,
+    weight: Option<f64>,
+}
+
+/// The mates of each pair that cover a pileup column, grouped by read name
+#[derive(Debug, Default)]
+struct MateOverlaps {
+    // indices of the alignments left out so each fragment is counted once
+    removed: HashSet<usize>,
+    pairs: u32,
+    // pairs whose mates have different alleles at the column
+    conflicts: u32,
+}
+
+/// Why an ambiguous position was not reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FailReason {
+    // pileup depth below --depth
+    Depth,
+    // no minor allele remained once reads were filtered
+    ReadFilters,
+    // minor alleles removed by the strand bias check took the position below --threshold
+    StrandBias,
+    // minor allele proportion at or below --threshold
+    Threshold,
+    // listed in the --mask file with --mask-mode flag
+    Masked,
+    // no non-reference allele had a frequency between --min-af and --max-af
+    AlleleFrequency,
+}
+
+impl FailReason {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            FailReason::Depth => "depth",
+            FailReason::ReadFilters => "read_filters",
+            FailReason::StrandBias => "strand_bias",
+            FailReason::Threshold => "threshold",
+            FailReason::Masked => "masked",
+            FailReason::AlleleFrequency => "allele_frequency",
+        }
+    }
+}
+
+/// The outcome of the ambiguity filters at a single position
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PositionResult {
+    // 1-based position
+    pub(crate) pos: u32,
+    // depth reported by the pileup, before any read filtering
+    pub(crate) depth: u32,
+    // reads that contributed an allele after read filtering
+    pub(crate) filtered_depth: u32,
+    pub(crate) pileup: Pileup,
+    pub(crate) major: char,
+    // minor alleles that failed the strand bias check
+    pub(crate) strand_failed: Vec<char>,
+    // proportion of each allele that passed the strand bias check
+    pub(crate) proportions: BTreeMap<char, f64>,
+    pub(crate) minor_proportion: f64,
+    // the major allele and every minor allele above --threshold on its own, 3 or more is a
+    // multi-allelic site
+    pub(crate) allele_count: u32,
+    pub(crate) passed: bool,
+    // empty if the position passed
+    pub(crate) reasons: Vec<FailReason>,
+    pub(crate) removed: ReadFilterCounts,
+    // overlapping pairs whose mates have different alleles, only counted with --dedup-overlaps
+    pub(crate) mate_conflicts: u32,
+    pub(crate) context: Option<SequenceContext>,
+}
+
+impl PositionResult {
+    /// The most common minor allele that passed the strand bias check, with its proportion
+    pub(crate) fn second_allele(&self) -> Option<(char, f64)> {
+        self.proportions
+            .iter()
+            .filter(|(allele, _)| **allele != self.major)
+            .map(|(allele, proportion)| (*allele, *proportion))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Whether three or more alleles are each above --threshold
+    pub(crate) fn is_multiallelic(&self) -> bool {
+        self.allele_count >= 3
+    }
+}
+
+/// A position where the most common allele differs from the reference base
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct FixedDifference {
+    // 1-based position
+    pub(crate) pos: u32,
+    pub(crate) reference: char,
+    pub(crate) consensus: char,
+    // proportion of the counted reads supporting the consensus allele
+    pub(crate) proportion: f64,
+    pub(crate) depth: u32,
+    pub(crate) total: u32,
+}
+
+/// Every ambiguous position found on a contig
+pub(crate) struct ContigScan {
+    pub(crate) positions_scanned: u32,
+    // positions with a depth of at least --depth
+    pub(crate) positions_covered: u32,
+    // positions with more than one allele before read filtering
+    pub(crate) positions_ambiguous: u32,
+    // positions listed in the --mask file
+    pub(crate) positions_masked: u32,
+    // reads removed by each read filter, summed over every scanned position
+    pub(crate) reads_removed: ReadFilterCounts,
+    // pairs with both mates covering a position and those whose mates disagree, summed over every
+    // scanned position, only counted with --dedup-overlaps
+    pub(crate) overlapping_pairs: u32,
+    pub(crate) mate_conflicts: u32,
+    // covered positions whose consensus differs from the reference, None without --reference
+    pub(crate) fixed_differences: Option<Vec<FixedDifference>>,
+    // positions that passed, masked positions that would have passed with --mask-mode flag,
+    // and those that failed when --report-all is set
+    pub(crate) positions: Vec<PositionResult>,
+    // (1-based position, depth) of every scanned position, only kept for the overview plot
+    #[cfg_attr(not(feature = "plot"), allow(dead_code))]
+    pub(crate) depths: Vec<(u32, u32)>,
+}
+
+/// Per contig figures used to judge whether a sample looks mixed, contaminated or clean
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ContigSummary {
+    pub(crate) positions_scanned: u32,
+    pub(crate) positions_covered: u32,
+    pub(crate) positions_ambiguous: u32,
+    pub(crate) positions_masked: u32,
+    pub(crate) positions_passed: u32,
+    // passing positions with three or more alleles above --threshold
+    pub(crate) positions_multiallelic: u32,
+    // None without --reference
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) fixed_differences: Option<u32>,
+    // passing positions per 1000 scanned positions
+    pub(crate) passed_per_kb: f64,
+    // None when no position passed
+    pub(crate) median_minor_proportion: Option<f64>,
+    pub(crate) overlapping_pairs: u32,
+    pub(crate) mate_conflicts: u32,
+    pub(crate) reads_removed: ReadFilterCounts,
+}
+
+impl ContigScan {
+    /// The positions that passed every filter
+    pub(crate) fn passed(&self) -> Vec<&PositionResult> {
+        self.positions
+            .iter()
+            .filter(|result| result.passed)
+            .collect()
+    }
+
+    pub(crate) fn summary(&self) -> ContigSummary {
+        let mut minor_proportions: Vec<f64> = self
+            .passed()
+            .iter()
+            .map(|result| result.minor_proportion)
+            .collect();
+        minor_proportions.sort_by(|a, b| a.total_cmp(b));
+        let positions_passed = minor_proportions.len() as u32;
+        let median_minor_proportion = match minor_proportions.len() {
+            0 => None,
+            len if len % 2 == 0 => {
+                Some((minor_proportions[len / 2 - 1] + minor_proportions[len / 2]) / 2.0)
+            }
+            len => Some(minor_proportions[len / 2]),
+        };
+        let passed_per_kb = if self.positions_scanned == 0 {
+            0.0
+        } else {
+            positions_passed as f64 * 1000.0 / self.positions_scanned as f64
+        };
+        ContigSummary {
+            positions_scanned: self.positions_scanned,
+            positions_covered: self.positions_covered,
+            positions_ambiguous: self.positions_ambiguous,
+            positions_masked: self.positions_masked,
+            positions_passed,
+            positions_multiallelic: self
+                .passed()
+                .iter()
+                .filter(|result| result.is_multiallelic())
+                .count() as u32,
+            fixed_differences: self
+                .fixed_differences
+                .as_ref()
+                .map(|differences| differences.len() as u32),
+            passed_per_kb: (passed_per_kb * 100.0).round() / 100.0,
+            median_minor_proportion: median_minor_proportion
+                .map(|median| (median * 10000.0).round() / 10000.0),
+            overlapping_pairs: self.overlapping_pairs,
+            mate_conflicts: self.mate_conflicts,
+            reads_removed: self.reads_removed.clone(),
+        }
+    }
+}
+
+/// Allele proportions of the positions that passed every filter, keyed by position
+pub(crate) fn passing_proportions(scan: &ContigScan) -> BTreeMap<u32, BTreeMap<char, f64>> {
+    scan.passed()
+        .iter()
+        .map(|result| (result.pos, result.proportions.clone()))
+        .collect()
+}
+
+// Above this many positions on one plot the bars are too narrow to label
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+const MAX_LABELLED_POSITIONS: usize = 60;
+
+/// Output kind of one page of a plot, only numbered when a plot is split over several pages
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+fn page_kind(kind: &str, page: usize, pages: usize) -> String {
+    if pages > 1 {
+        format!("{}.page{}", kind, page)
+    } else {
+        kind.to_string()
+    }
+}
+
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+fn page_title(title: &str, page: usize, pages: usize) -> String {
+    if pages > 1 {
+        format!("{} (page {} of {})", title, page, pages)
+    } else {
+        title.to_string()
+    }
+}
+
+/// Mann-Whitney rank sum z-score of `x` against `y` using the normal approximation with a tie
+/// correction. None when either sample is empty or every value is tied
+fn rank_sum_z(x: &[u32], y: &[u32]) -> Option<f64> {
+    if x.is_empty() || y.is_empty() {
+        return None;
+    }
+    let mut values: Vec<(u32, bool)> = x
+        .iter()
+        .map(|value| (*value, true))
+        .chain(y.iter().map(|value| (*value, false)))
+        .collect();
+    values.sort_by_key(|(value, _)| *value);
+
+    // tied values share the mean of the ranks they span
+    let n = values.len();
+    let mut rank_sum_x = 0.0;
+    let mut ties = 0.0;
+    let mut start = 0;
+    while start < n {
+        let mut end = start;
+        while end < n && values[end].0 == values[start].0 {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        let tied = (end - start) as f64;
+        ties += tied.powi(3) - tied;
+        rank_sum_x += rank * values[start..end].iter().filter(|(_, in_x)| *in_x).count() as f64;
+        start = end;
+    }
+
+    let (nx, ny, n) = (x.len() as f64, y.len() as f64, n as f64);
+    let u = rank_sum_x - nx * (nx + 1.0) / 2.0;
+    let variance = nx * ny / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if variance <= 0.0 {
+        return None;
+    }
+    Some((u - nx * ny / 2.0) / variance.sqrt())
+}
+
+/// Distance of a 0-based reference position from the nearer end of the aligned part of a read,
+/// soft clipped bases are not aligned so do not count towards it
+fn read_end_distance(record: &bam::Record, pos: u32) -> u32 {
+    let pos = pos as i64;
+    let start = record.pos();
+    let end = record.cigar().end_pos();
+    (pos - start).min(end - 1 - pos).max(0) as u32
+}
+
+/// Probability that a Phred scaled quality is correct, 255 (quality unavailable) counts fully
+fn phred_weight(quality: u8) -> f64 {
+    if quality == 255 {
+        return 1.0;
+    }
+    1.0 - 10f64.powf(-(quality as f64) / 10.0)
+}
+
+/// The alleles a read supports at a pileup column, a read can carry a base and an insertion
+fn read_alleles(record: &bam::Record, alignment: &bam::pileup::Alignment) -> Vec<char> {
+    let mut alleles = Vec::new();
+    if alignment.is_del() {
+        alleles.push('-');
+    }
+    // if the read is not a deletion or a refskip then we have a real base
+    if let Some(qpos) = alignment.qpos() {
+        let base = (record.seq()[qpos] as char).to_ascii_uppercase();
+        if matches!(base, 'A' | 'T' | 'C' | 'G') {
+            alleles.push(base);
+        }
+    }
+    // Check if insertion
+    if let bam::pileup::Indel::Ins(_len) = alignment.indel() {
+        alleles.push('+');
+    }
+    alleles
+}
+
+/// Query position of the first base after a deletion covering the 0-based reference position,
+/// None when the read has no deletion there
+fn deletion_qpos(record: &bam::Record, pos: u32) -> Option<usize> {
+    let pos = pos as i64;
+    let mut ref_pos = record.pos();
+    let mut qpos = 0;
+    for op in record.cigar().iter() {
+        let len = op.len() as usize;
+        match op {
+            Cigar::Del(_) if (ref_pos..ref_pos + len as i64).contains(&pos) => return Some(qpos),
+            Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) => {
+                ref_pos += len as i64;
+                qpos += len;
+            }
+            Cigar::Del(_) | Cigar::RefSkip(_) => ref_pos += len as i64,
+            Cigar::Ins(_) | Cigar::SoftClip(_) => qpos += len,
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    None
+}
+
+/// An alignment identified by its tid, start, flags and read name
+type ReadKey = (i32, i64, u16, Vec<u8>);
+
 pub struct Ambig<'a> {
-    input: &'a str,
-    chrom: Option<&'a str>,
-    start: u32,
-    stop: u32,
-    no_indel: bool,
-    threshold: f64,
-    no_label: bool,
-    output: String,
-    base_quality_threshold: u8,
-    map_quality_threshold: u8,
-    depth_threshold: u32,
-    strand_bias_threshold: f64,
-    bed: bool,
+    pub(crate) input: &'a str,
+    pub(crate) chrom: Option<&'a str>,
+    pub(crate) start: u32,
+    pub(crate) stop: u32,
+    pub(crate) settings: Settings,
+    output: OutputPaths,
+    provenance: Provenance,
+    reference: Option<Reference>,
+    mask: Option<Mask>,
+    // BAQ qualities and end position of the reads in the current pileup column
+    baq_cache: RefCell<HashMap<ReadKey, (i64, Vec<u8>)>>,
 }
 
 impl<'a> Ambig<'a> {
@@ -132,34 +651,44 @@ impl<'a> Ambig<'a> {
         chrom: Option<&'a str>,
         start: Option<u32>,
         stop: Option<u32>,
-        no_indel: bool,
-        threshold: f64,
-        no_label: bool,
-        output: String,
-        base_quality_threshold: u8,
-        map_quality_threshold: u8,
-        depth_threshold: u32,
-        strand_bias_threshold: f64,
-        bed: bool,
-    ) -> Self {
+        settings: Settings,
+        output: OutputPaths,
+    ) -> Result<Self, String> {
         let (start, stop) = get_start_stop(start, stop);
-        Self {
+        let output = output.for_region(chrom, start, stop);
+        let provenance = Provenance::new(
+            vec![input.to_string()],
+            chrom,
+            start,
+            stop,
+            Some(RunSettings::Scan(Box::new(settings.clone()))),
+        );
+        let reference = settings
+            .reference
+            .as_deref()
+            .map(Reference::from_path)
+            .transpose()?;
+        let mask = settings.mask.as_deref().map(Mask::from_path).transpose()?;
+        Ok(Self {
             input,
             chrom,
             start,
             stop,
-            no_indel,
-            threshold,
-            no_label,
+            settings,
             output,
-            base_quality_threshold,
-            map_quality_threshold,
-            depth_threshold,
-            strand_bias_threshold,
-            bed,
-        }
+            provenance,
+            reference,
+            mask,
+            baq_cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// The reference sequence around a 0-based position, None without --reference
+    pub(crate) fn context(&self, contig: &str, pos: u32) -> Option<SequenceContext> {
+        self.reference.as_ref()?.context(contig, pos)
     }
 
+    #[allow(dead_code)]
     fn output_tsv(&self, pos_to_plot: &BTreeMap<u32, BTreeMap<char, f64>>) {
         let mut file = File::create("output.tsv").expect("Failed to create file");
         // Iterate through the outer map
@@ -170,25 +699,47 @@ impl<'a> Ambig<'a> {
                 write!(file, "{}\t{}\t", key2, value).expect("Failed to write to file");
             }
             // Write a newline character to start a new row
-            writeln!(file, "").expect("Failed to write to file");
+            writeln!(file).expect("Failed to write to file");
         }
     }
 
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn title_text(&self, plot: &str, tid: &str) -> String {
+        self.settings.style.title(
+            plot,
+            &sample_name(self.input),
+            tid,
+            &format_region(Some(tid), self.start, self.stop),
+        )
+    }
+
+    /// The styled plot title with the run provenance as a subtitle
+    #[cfg(feature = "plot")]
+    fn title(&self, plot: &str, tid: &str) -> Title {
+        Title::new(&format!(
+            "{}<br><sup>{}</sup>",
+            self.title_text(plot, tid),
+            self.provenance.subtitle()
+        ))
+    }
+
+    #[cfg(all(feature = "plot", any(feature = "html", not(feature = "native-plot"))))]
     fn create_bar(
         &self,
         name: &str,
         colour: &str,
         pos: Vec<u32>,
         bases: Vec<f64>,
+        labels: bool,
     ) -> Box<Bar<u32, f64>> {
         let cloned_colour = colour.to_string();
         let mut bar = Bar::new(pos, bases.clone()).name(name);
-        if !self.no_label {
+        if labels {
             bar = bar
                 .text_array(
                     bases
                         .iter()
-                        .map(|x| format!("{:.2}", x))
+                        .map(|x| self.label_text(*x))
                         .collect::<Vec<String>>(),
                 )
                 .text_position(TextPosition::Inside)
@@ -197,54 +748,510 @@ impl<'a> Ambig<'a> {
         bar.marker(Marker::new().color(cloned_colour))
     }
 
-    fn plot(&self, pos_to_plot: &BTreeMap<u32, BTreeMap<char, f64>>, tid: &str) {
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn label_text(&self, value: f64) -> String {
+        match self.settings.plot_values {
+            PlotValues::Proportions => format!("{:.2}", value),
+            PlotValues::Counts => format!("{}", value),
+        }
+    }
+
+    /// Bar heights of each allele at every position, with alleles that failed the strand bias
+    /// filter left out
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn bar_values(&self, passed: &[&PositionResult]) -> Vec<(char, Vec<f64>)> {
+        ['A', 'C', 'G', 'T', '-', '+']
+            .iter()
+            .map(|allele| {
+                let values: Vec<f64> = passed
+                    .iter()
+                    .map(|result| match result.proportions.get(allele) {
+                        None => 0.0,
+                        Some(proportion) => match self.settings.plot_values {
+                            PlotValues::Proportions => *proportion,
+                            PlotValues::Counts => result.pileup.get_base_count(*allele) as f64,
+                        },
+                    })
+                    .collect();
+                (*allele, values)
+            })
+            .collect()
+    }
+
+    /// One stacked bar trace per allele, coloured consistently across every plot
+    #[cfg(all(feature = "plot", any(feature = "html", not(feature = "native-plot"))))]
+    pub(crate) fn bar_traces(&self, passed: &[&PositionResult]) -> Vec<Bar<u32, f64>> {
         // collect all posisitons for x-axis
-        let pos: Vec<u32> = pos_to_plot.keys().cloned().collect();
-
-        let mut a: Vec<f64> = Vec::new();
-        let mut c: Vec<f64> = Vec::new();
-        let mut g: Vec<f64> = Vec::new();
-        let mut t: Vec<f64> = Vec::new();
-        let mut del: Vec<f64> = Vec::new();
-        let mut ins: Vec<f64> = Vec::new();
-
-        for base_counts in pos_to_plot.values() {
-            a.push(*base_counts.get(&'A').unwrap_or(&0.0));
-            c.push(*base_counts.get(&'C').unwrap_or(&0.0));
-            g.push(*base_counts.get(&'G').unwrap_or(&0.0));
-            t.push(*base_counts.get(&'T').unwrap_or(&0.0));
-            del.push(*base_counts.get(&'-').unwrap_or(&0.0));
-            ins.push(*base_counts.get(&'+').unwrap_or(&0.0));
-        }
-
-        let traces = vec![
-            self.create_bar("A", "#60935D", pos.clone(), a.clone()),
-            self.create_bar("C", "#1B5299", pos.clone(), c.clone()),
-            self.create_bar("G", "#F5BB00", pos.clone(), g.clone()),
-            self.create_bar("T", "#E63946", pos.clone(), t.clone()),
-            self.create_bar("-", "#000000", pos.clone(), del.clone()),
-            self.create_bar("+", "#6A041D", pos.clone(), ins.clone()),
-        ];
+        let pos: Vec<u32> = passed.iter().map(|result| result.pos).collect();
+        let labels = self.show_labels(passed.len());
+
+        self.bar_values(passed)
+            .into_iter()
+            .map(|(allele, values)| {
+                *self.create_bar(
+                    &allele.to_string(),
+                    &self.settings.style.colour(&allele.to_string()),
+                    pos.clone(),
+                    values,
+                    labels,
+                )
+            })
+            .collect()
+    }
+
+    /// Labels are hidden when the bars would be too narrow to fit them
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn show_labels(&self, positions: usize) -> bool {
+        !self.settings.no_label && positions <= MAX_LABELLED_POSITIONS
+    }
+
+    /// Split positions into pages of at most --max-positions-per-plot, always at least one page
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn pages<'b>(&self, passed: &'b [&'b PositionResult]) -> Vec<&'b [&'b PositionResult]> {
+        let max = self.settings.max_positions_per_plot;
+        if max == 0 || passed.is_empty() {
+            return vec![passed];
+        }
+        passed.chunks(max).collect()
+    }
+
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn y_title(&self) -> &'static str {
+        match self.settings.plot_values {
+            PlotValues::Proportions => "Proportion",
+            PlotValues::Counts => "Reads",
+        }
+    }
+
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn plot(&self, passed: &[&PositionResult], tid: &str, page: usize, pages: usize) -> String {
+        #[cfg(feature = "native-plot")]
+        let ext = "png";
+        #[cfg(not(feature = "native-plot"))]
+        let ext = PLOT_EXT;
+        let out_name = self.output.contig_path(
+            tid,
+            self.start,
+            self.stop,
+            &page_kind("ambig", page, pages),
+            ext,
+        );
+        self.render_bar(
+            passed,
+            &page_title("Ambiguous Bases", page, pages),
+            tid,
+            &out_name,
+        );
+        out_name
+    }
+
+    #[cfg(all(feature = "plot", not(feature = "native-plot")))]
+    fn render_bar(&self, passed: &[&PositionResult], title: &str, tid: &str, out_name: &str) {
+        let traces = self.bar_traces(passed);
         let layout = plotly::Layout::new()
             .bar_mode(BarMode::Stack)
-            .title(Title::new("Ambiguous Bases"))
+            .title(self.title(title, tid))
+            .annotations(vec![self.provenance.annotation()])
             .x_axis(Axis::new().title(Title::new("Position")).type_(Category))
-            .y_axis(Axis::new().title(Title::new("Proportion")));
+            .y_axis(Axis::new().title(Title::new(self.y_title())))
+            .y_axis2(
+                Axis::new()
+                    .title(Title::new("Depth"))
+                    .overlaying("y")
+                    .side(plotly::common::AxisSide::Right)
+                    .show_grid(false),
+            );
         let mut plot = plotly::Plot::new();
         for trace in traces {
-            plot.add_trace(trace);
+            plot.add_trace(Box::new(trace));
         }
-        plot.set_layout(layout);
+        // depth at each position as a marker against the right hand axis
+        let depth = Scatter::new(
+            passed.iter().map(|result| result.pos).collect(),
+            passed.iter().map(|result| result.depth).collect(),
+        )
+        .name("Depth")
+        .mode(Mode::Markers)
+        .marker(
+            Marker::new()
+                .color("#555555")
+                .symbol(plotly::common::MarkerSymbol::Diamond)
+                .size(12),
+        )
+        .y_axis("y2");
+        plot.add_trace(depth);
+        // multi-allelic positions are marked over the top of their bar
+        let values = self.bar_values(passed);
+        let (marked_x, marked_y): (Vec<u32>, Vec<f64>) = passed
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.is_multiallelic())
+            .map(|(index, result)| {
+                let top: f64 = values.iter().map(|(_, values)| values[index]).sum();
+                (result.pos, top)
+            })
+            .unzip();
+        if !marked_x.is_empty() {
+            let marks = Scatter::new(marked_x, marked_y)
+                .name("Multi-allelic")
+                .mode(Mode::Markers)
+                .marker(
+                    Marker::new()
+                        .color("#222222")
+                        .symbol(plotly::common::MarkerSymbol::StarOpen)
+                        .size(14),
+                );
+            plot.add_trace(marks);
+        }
+        plot.set_layout(self.settings.style.apply(layout));
+        write_plot(&plot, out_name, 2000, 1000);
+    }
+
+    #[cfg(feature = "native-plot")]
+    fn render_bar(&self, passed: &[&PositionResult], title: &str, tid: &str, out_name: &str) {
+        let labels = self.show_labels(passed.len());
+        let series: Vec<BarSeries> = self
+            .bar_values(passed)
+            .into_iter()
+            .map(|(allele, values)| BarSeries {
+                name: allele.to_string(),
+                colour: self.settings.style.colour(&allele.to_string()),
+                labels: if labels {
+                    values.iter().map(|value| self.label_text(*value)).collect()
+                } else {
+                    Vec::new()
+                },
+                values,
+            })
+            .collect();
+        render::bar_chart(
+            out_name,
+            &Titles {
+                title: &self.title_text(title, tid),
+                subtitle: &self.provenance.subtitle(),
+                footer: &self.provenance.command_line,
+            },
+            &passed
+                .iter()
+                .map(|result| BarPosition {
+                    pos: result.pos,
+                    depth: result.depth,
+                    multiallelic: result.is_multiallelic(),
+                })
+                .collect::<Vec<BarPosition>>(),
+            &series,
+            self.y_title(),
+            &self.settings.style,
+        );
+    }
+
+    /// One bar trace per allele with forward strand counts above the axis and reverse strand
+    /// counts below it, hover text giving each count and the strand bias result
+    #[cfg(feature = "plot")]
+    fn strand_traces(&self, passed: &[&PositionResult]) -> Vec<Bar<u32, i64>> {
+        let mut traces = Vec::new();
+        for allele in ['A', 'C', 'G', 'T', '-', '+'] {
+            if passed
+                .iter()
+                .all(|result| result.pileup.get_base_count(allele) == 0)
+            {
+                continue;
+            }
+            let mut x = Vec::new();
+            let mut y = Vec::new();
+            let mut hover = Vec::new();
+            for result in passed {
+                let (forward, reverse) = result.pileup.get_strand_counts(allele);
+                let strand_bias = if result.strand_failed.contains(&allele) {
+                    "fail"
+                } else {
+                    "pass"
+                };
+                x.extend([result.pos, result.pos]);
+                y.extend([forward as i64, -(reverse as i64)]);
+                hover.extend([
+                    format!(
+                        "{} forward: {}<br>strand bias: {}",
+                        allele, forward, strand_bias
+                    ),
+                    format!(
+                        "{} reverse: {}<br>strand bias: {}",
+                        allele, reverse, strand_bias
+                    ),
+                ]);
+            }
+            let mut bar = Bar::new(x, y.clone())
+                .name(allele.to_string())
+                .hover_text_array(hover)
+                .marker(Marker::new().color(self.settings.style.colour(&allele.to_string())));
+            if self.show_labels(passed.len()) {
+                bar = bar
+                    .text_array(
+                        y.iter()
+                            .map(|count| match count {
+                                0 => String::new(),
+                                count => count.abs().to_string(),
+                            })
+                            .collect(),
+                    )
+                    .text_position(TextPosition::Inside)
+                    .inside_text_anchor(TextAnchor::Middle);
+            }
+            traces.push(*bar);
+        }
+        traces
+    }
+
+    /// Forward strand counts above the axis and reverse strand counts mirrored below it, so the
+    /// strand balance behind each flagged position can be checked
+    #[cfg(feature = "plot")]
+    fn plot_strand(
+        &self,
+        passed: &[&PositionResult],
+        tid: &str,
+        page: usize,
+        pages: usize,
+    ) -> String {
+        let mut plot = plotly::Plot::new();
+        for bar in self.strand_traces(passed) {
+            plot.add_trace(Box::new(bar));
+        }
+        let layout = plotly::Layout::new()
+            .bar_mode(BarMode::Relative)
+            .title(self.title(&page_title("Ambiguous Bases by Strand", page, pages), tid))
+            .annotations(vec![self.provenance.annotation()])
+            .x_axis(Axis::new().title(Title::new("Position")).type_(Category))
+            .y_axis(Axis::new().title(Title::new("Reads (forward above, reverse below)")));
+        plot.set_layout(self.settings.style.apply(layout));
 
-        let out_name = format!("{}_{}.png", tid, self.output);
-        plot.write_image(out_name, ImageFormat::PNG, 2000, 1000, 1.0);
+        let out_name = self.output.contig_path(
+            tid,
+            self.start,
+            self.stop,
+            &page_kind("strand", page, pages),
+            PLOT_EXT,
+        );
+        write_plot(&plot, &out_name, 2000, 1000);
+        out_name
     }
 
-    fn filter_base_counts(&self, pos: u32, pileup: &Pileup) -> BTreeMap<u32, BTreeMap<char, f64>> {
-        let mut global_base_counts: BTreeMap<u32, BTreeMap<char, f64>> = BTreeMap::new();
+    /// Flagged positions as lollipops sized by minor allele proportion on a linear genome axis,
+    /// above a gene track (if an annotation was given) and the depth
+    #[cfg(feature = "plot")]
+    fn plot_overview(&self, scan: &ContigScan, features: &[Feature], tid: &str) -> String {
+        let passed = scan.passed();
 
-        // First sum all the bases so we can calculate the percent later
-        let total_count: u32 = pileup.get_total();
+        // stems are drawn as one line broken by gaps between positions
+        let mut stem_x = Vec::new();
+        let mut stem_y = Vec::new();
+        for result in &passed {
+            stem_x.extend([result.pos, result.pos, result.pos]);
+            stem_y.extend([Some(0.0), Some(result.minor_proportion), None]);
+        }
+        let stems = Scatter::new(stem_x, stem_y)
+            .mode(Mode::Lines)
+            .line(Line::new().color("#888888"))
+            .show_legend(false)
+            .hover_info(HoverInfo::Skip)
+            .y_axis("y3");
+        let heads = Scatter::new(
+            passed.iter().map(|result| result.pos).collect(),
+            passed
+                .iter()
+                .map(|result| result.minor_proportion)
+                .collect(),
+        )
+        .name("Minor allele proportion")
+        .mode(Mode::Markers)
+        .marker(
+            Marker::new()
+                .color(self.settings.style.colour("C"))
+                .size_array(
+                    passed
+                        .iter()
+                        .map(|result| 6 + (result.minor_proportion * 40.0) as usize)
+                        .collect(),
+                ),
+        )
+        .hover_text_array(
+            passed
+                .iter()
+                .map(|result| {
+                    let alleles: Vec<String> = result
+                        .proportions
+                        .iter()
+                        .map(|(allele, proportion)| format!("{}: {}", allele, proportion))
+                        .collect();
+                    format!("{}<br>depth: {}", alleles.join("<br>"), result.depth)
+                })
+                .collect(),
+        )
+        .y_axis("y3");
+
+        let (depth_x, depth_y): (Vec<u32>, Vec<u32>) = scan.depths.iter().cloned().unzip();
+        let depth = Scatter::new(depth_x, depth_y)
+            .name("Depth")
+            .mode(Mode::Lines)
+            .line(Line::new().color(self.settings.style.colour("depth")));
+
+        let mut plot = plotly::Plot::new();
+        plot.add_trace(depth);
+        plot.add_trace(stems);
+        plot.add_trace(heads);
+
+        // multi-allelic positions are marked over their lollipop head
+        let multiallelic: Vec<&&PositionResult> = passed
+            .iter()
+            .filter(|result| result.is_multiallelic())
+            .collect();
+        if !multiallelic.is_empty() {
+            let marks = Scatter::new(
+                multiallelic.iter().map(|result| result.pos).collect(),
+                multiallelic
+                    .iter()
+                    .map(|result| result.minor_proportion)
+                    .collect(),
+            )
+            .name("Multi-allelic")
+            .mode(Mode::Markers)
+            .marker(
+                Marker::new()
+                    .color("#222222")
+                    .symbol(plotly::common::MarkerSymbol::StarOpen)
+                    .size(14),
+            )
+            .hover_text_array(
+                multiallelic
+                    .iter()
+                    .map(|result| format!("{} alleles above the threshold", result.allele_count))
+                    .collect(),
+            )
+            .y_axis("y3");
+            plot.add_trace(marks);
+        }
+
+        let features: Vec<&Feature> = features
+            .iter()
+            .filter(|feature| feature.contig == tid)
+            .collect();
+        let mut layout = plotly::Layout::new()
+            .title(self.title("Ambiguous Bases Overview", tid))
+            .annotations(vec![self.provenance.annotation()])
+            .x_axis(Axis::new().title(Title::new("Position")))
+            .y_axis(Axis::new().title(Title::new("Depth")).domain(&[0.0, 0.3]))
+            .y_axis3(
+                Axis::new()
+                    .title(Title::new("Minor allele proportion"))
+                    .domain(&[0.45, 1.0])
+                    .anchor("x"),
+            );
+        if !features.is_empty() {
+            // genes alternate between two rows so neighbouring labels do not overlap
+            let mut gene_x = Vec::new();
+            let mut gene_y = Vec::new();
+            for (index, feature) in features.iter().enumerate() {
+                let row = (index % 2) as f64;
+                gene_x.extend([feature.start, feature.end, feature.end]);
+                gene_y.extend([Some(row), Some(row), None]);
+            }
+            let genes = Scatter::new(gene_x, gene_y)
+                .mode(Mode::Lines)
+                .line(
+                    Line::new()
+                        .color(self.settings.style.colour("+"))
+                        .width(8.0),
+                )
+                .show_legend(false)
+                .hover_info(HoverInfo::Skip)
+                .y_axis("y2");
+            let labels = Scatter::new(
+                features
+                    .iter()
+                    .map(|feature| (feature.start + feature.end) / 2)
+                    .collect(),
+                (0..features.len())
+                    .map(|index| (index % 2) as f64 + 0.45)
+                    .collect(),
+            )
+            .mode(Mode::Text)
+            .text_array(
+                features
+                    .iter()
+                    .map(|feature| feature.name.clone())
+                    .collect(),
+            )
+            .show_legend(false)
+            .y_axis("y2");
+            plot.add_trace(genes);
+            plot.add_trace(labels);
+            layout = layout.y_axis2(
+                Axis::new()
+                    .domain(&[0.32, 0.43])
+                    .range(vec![-0.5, 1.9])
+                    .show_tick_labels(false)
+                    .show_grid(false)
+                    .zero_line(false)
+                    .anchor("x"),
+            );
+        }
+        plot.set_layout(self.settings.style.apply(layout));
+
+        let out_name = self
+            .output
+            .contig_path(tid, self.start, self.stop, "overview", PLOT_EXT);
+        write_plot(&plot, &out_name, 2000, 1000);
+        out_name
+    }
+
+    /// The depth compared against --depth, the raw pileup depth or the reads left after filtering
+    pub(crate) fn compared_depth(&self, depth: u32, counts: &ColumnCounts) -> u32 {
+        match self.settings.depth_mode {
+            DepthMode::Raw => depth,
+            DepthMode::Filtered => counts.reads,
+        }
+    }
+
+    fn evaluate_position(&self, pos: u32, depth: u32, counts: ColumnCounts) -> PositionResult {
+        let compared_depth = self.compared_depth(depth, &counts);
+        let ColumnCounts {
+            pileup,
+            unfiltered,
+            removed,
+            reads: filtered_depth,
+            mate_conflicts,
+            context,
+            ..
+        } = counts;
+        let mut reasons = Vec::new();
+        if compared_depth < self.settings.depth_threshold {
+            reasons.push(FailReason::Depth);
+        }
+
+        // Nothing left to evaluate if read filtering removed every minor allele
+        if !pileup.is_ambiguous() {
+            reasons.push(FailReason::ReadFilters);
+            let major = if pileup.get_total() > 0 {
+                pileup.get_major_variant()
+            } else {
+                unfiltered.get_major_variant()
+            };
+            return PositionResult {
+                pos: pos + 1,
+                depth,
+                filtered_depth,
+                pileup,
+                major,
+                strand_failed: Vec::new(),
+                proportions: BTreeMap::new(),
+                minor_proportion: 0.0,
+                allele_count: 1,
+                passed: false,
+                reasons,
+                removed,
+                mate_conflicts,
+                context,
+            };
+        }
 
         // First find the major variant (base with most reads)
         let major_variant = pileup.get_major_variant();
@@ -271,182 +1278,868 @@ impl<'a> Ambig<'a> {
                 continue;
             }
             if base != major_variant
-                && (ratio < self.strand_bias_threshold || ratio > 1.0 - self.strand_bias_threshold)
+                && (ratio < self.settings.strand_bias_threshold
+                    || ratio > 1.0 - self.settings.strand_bias_threshold)
             {
                 failed_bases.push(base);
             }
         }
 
         // Calculate the percent of each base and round to 4 decimal places, ignoring bases with 0 counts
-        let percent_base_counts: BTreeMap<char, f64> = [
-            ('A', pileup.a.0 as f64 / total_count as f64),
-            ('C', pileup.c.0 as f64 / total_count as f64),
-            ('G', pileup.g.0 as f64 / total_count as f64),
-            ('T', pileup.t.0 as f64 / total_count as f64),
-            ('-', pileup.del.0 as f64 / total_count as f64),
-            ('+', pileup.ins.0 as f64 / total_count as f64),
-        ]
-        .iter()
-        .cloned()
-        .filter(|(base, percent)| *percent > 0.0 && !failed_bases.contains(base))
-        .map(|(base, percent)| (base, (percent * 10000.0).round() / 10000.0))
-        .collect();
+        // weighted counts are used in place of read counts when --weighting is set
+        let percent_base_counts: BTreeMap<char, f64> = ['A', 'C', 'G', 'T', '-', '+']
+            .iter()
+            .map(|base| (*base, pileup.get_proportion(*base)))
+            .filter(|(base, percent)| *percent > 0.0 && !failed_bases.contains(base))
+            .map(|(base, percent)| (base, (percent * 10000.0).round() / 10000.0))
+            .collect();
 
-        // Find the proportion of minor variants
+        // Find the proportion of minor variants, an empty float sum is -0.0 so start from 0.0
         let total_minor_proportion = percent_base_counts
             .iter()
             .filter(|(base, _)| *base != &major_variant)
             .map(|(_, percent)| percent)
-            .sum::<f64>();
+            .fold(0.0, |sum, percent| sum + percent);
 
-        // If the sum of the minor variant proportion is greater than the threshold, we will plot
-        if total_minor_proportion > self.threshold {
-            global_base_counts.insert(pos + 1, percent_base_counts);
+        // alleles other than the reference base, or the major allele without --reference, must
+        // have a frequency within --min-af and --max-af
+        if self.settings.has_af_band() {
+            let reference = context
+                .as_ref()
+                .map_or(major_variant, |context| context.base);
+            let in_band = percent_base_counts.iter().any(|(base, percent)| {
+                *base != reference
+                    && (self.settings.min_af..=self.settings.max_af).contains(percent)
+            });
+            if !in_band {
+                reasons.push(FailReason::AlleleFrequency);
+            }
+        }
+
+        let allele_count = 1 + percent_base_counts
+            .iter()
+            .filter(|(base, percent)| {
+                **base != major_variant && **percent > self.settings.threshold
+            })
+            .count() as u32;
+
+        // If the minor variant proportion is greater than the threshold, we will plot
+        let minor_passed = match self.settings.threshold_mode {
+            ThresholdMode::Total => total_minor_proportion > self.settings.threshold,
+            ThresholdMode::PerAllele => allele_count > 1,
+        };
+        if !minor_passed {
+            let unstranded_minor_proportion = match self.settings.threshold_mode {
+                ThresholdMode::Total => 1.0 - pileup.get_proportion(major_variant),
+                ThresholdMode::PerAllele => ['A', 'C', 'G', 'T', '-', '+']
+                    .iter()
+                    .filter(|base| **base != major_variant)
+                    .map(|base| pileup.get_proportion(*base))
+                    .fold(0.0, f64::max),
+            };
+            if !failed_bases.is_empty() && unstranded_minor_proportion > self.settings.threshold {
+                reasons.push(FailReason::StrandBias);
+            } else {
+                reasons.push(FailReason::Threshold);
+            }
+        }
+        PositionResult {
+            pos: pos + 1,
+            depth,
+            filtered_depth,
+            pileup,
+            major: major_variant,
+            strand_failed: failed_bases,
+            proportions: percent_base_counts,
+            minor_proportion: total_minor_proportion,
+            allele_count,
+            passed: reasons.is_empty(),
+            reasons,
+            removed,
+            mate_conflicts,
+            context,
         }
-        global_base_counts
     }
 
-    fn is_qc_pass(
+    /// The read level filter that excludes this read, if any
+    fn qc_filter(
         &self,
         record: &bam::Record,
-        pileup: &bam::pileup::Pileup,
-        alignment: &bam::pileup::Alignment,
-    ) -> bool {
-        // Checking the seq isnt empty is necessary as secondary alignments can cause empty seqs
-        if record.seq().is_empty() || pileup.depth() < self.depth_threshold {
-            return false;
-        }
+        quality: Option<u8>,
+        end_distance: u32,
+    ) -> Option<ReadFilter> {
         // check for base Q score and map Q score
-        if let Some(qpos) = alignment.qpos() {
-            if record.qual()[qpos] < self.base_quality_threshold {
-                return false;
+        if let Some(quality) = quality {
+            if quality < self.settings.base_quality_threshold {
+                return Some(ReadFilter::BaseQuality);
             }
-            if record.mapq() < self.map_quality_threshold {
-                return false;
+            if record.mapq() < self.settings.map_quality_threshold {
+                return Some(ReadFilter::MapQuality);
             }
         }
-        true
+        if end_distance < self.settings.read_end_distance {
+            return Some(ReadFilter::ReadEnd);
+        }
+        None
     }
 
-    fn produce_pileup(&self, bam: &mut bam::IndexedReader) -> BTreeMap<u32, BTreeMap<char, f64>> {
-        let mut filtered_pileup_counts: BTreeMap<u32, BTreeMap<char, f64>> = BTreeMap::new();
-        for pileup in bam.pileup().flatten() {
-            let mut pileup_struct = Pileup::new();
-            if pileup.pos() < self.start || pileup.pos() >= self.stop {
+    /// Weight of a read's allele from its base quality, and mapping quality with bq-mq, as the
+    /// probability the base and alignment are correct. None when --weighting is not set
+    fn read_weight(
+        &self,
+        record: &bam::Record,
+        quality: Option<u8>,
+        pos: u32,
+        contig: &str,
+    ) -> Option<f64> {
+        if self.settings.weighting == Weighting::None {
+            return None;
+        }
+        // deletions have no base quality of their own, so take the lower quality of the bases
+        // either side of them
+        let quality = quality.or_else(|| {
+            let qpos = deletion_qpos(record, pos)?;
+            [qpos.checked_sub(1), Some(qpos)]
+                .into_iter()
+                .flatten()
+                .filter(|flank| *flank < record.seq_len())
+                .map(|flank| self.quality_at(record, flank, contig))
+                .min()
+        });
+        let base_quality = quality.map_or(1.0, phred_weight);
+        match self.settings.weighting {
+            Weighting::None => None,
+            Weighting::Bq => Some(base_quality),
+            Weighting::BqMq => Some(base_quality * phred_weight(record.mapq())),
+        }
+    }
+
+    /// Quality of a read's base at the column, recalculated with BAQ when --baq is set. None for
+    /// deletions, which have no base of their own
+    fn base_quality(
+        &self,
+        record: &bam::Record,
+        alignment: &bam::pileup::Alignment,
+        contig: &str,
+    ) -> Option<u8> {
+        let qpos = alignment.qpos()?;
+        Some(self.quality_at(record, qpos, contig))
+    }
+
+    /// Quality of the base at a query position, recalculated with BAQ when --baq is set
+    fn quality_at(&self, record: &bam::Record, qpos: usize, contig: &str) -> u8 {
+        let reference = match &self.reference {
+            Some(reference) if self.settings.baq => reference,
+            _ => return record.qual()[qpos],
+        };
+        let key = (
+            record.tid(),
+            record.pos(),
+            record.flags(),
+            record.qname().to_vec(),
+        );
+        let mut cache = self.baq_cache.borrow_mut();
+        let (_, qualities) = cache.entry(key).or_insert_with(|| {
+            (
+                record.cigar().end_pos(),
+                reference
+                    .baq_qualities(record, contig)
+                    .unwrap_or_else(|| record.qual().to_vec()),
+            )
+        });
+        qualities[qpos]
+    }
+
+    /// Group the reads that passed the read filters by read name so each fragment is counted
+    /// once. The mate with the higher base quality is kept, the first of two with equal quality,
+    /// and with --mate-conflict discard neither mate is kept when their alleles differ
+    fn overlapping_mates(&self, reads: &[ColumnRead]) -> MateOverlaps {
+        let mut kept: HashMap<&[u8], (usize, u8, &[char])> = HashMap::new();
+        let mut overlaps = MateOverlaps::default();
+        for (index, read) in reads.iter().enumerate() {
+            let Some(name) = &read.name else {
                 continue;
-            }
-            //let mut base_counts: HashMap<char, u32> = HashMap::new();
-            for alignment in pileup.alignments() {
-                let record = alignment.record();
-                let orientation = record.strand().to_string();
-                if !self.is_qc_pass(&record, &pileup, &alignment) {
-                    continue;
+            };
+            let quality = read.quality.unwrap_or(0);
+            match kept.entry(name) {
+                Entry::Vacant(entry) => {
+                    entry.insert((index, quality, &read.alleles));
                 }
-                if self.no_indel && (alignment.is_refskip() || alignment.is_del()) {
-                    continue;
-                } else if alignment.is_del() {
-                    pileup_struct.del.0 += 1;
-                    if orientation == "+" {
-                        pileup_struct.del.1 .0 += 1;
+                Entry::Occupied(mut entry) => {
+                    let (mate, mate_quality, mate_alleles) = *entry.get();
+                    overlaps.pairs += 1;
+                    if mate_alleles != read.alleles {
+                        overlaps.conflicts += 1;
+                        if self.settings.mate_conflict == MateConflict::Discard {
+                            overlaps.removed.extend([mate, index]);
+                            continue;
+                        }
+                    }
+                    if quality > mate_quality {
+                        overlaps.removed.insert(mate);
+                        entry.insert((index, quality, &read.alleles));
                     } else {
-                        pileup_struct.del.1 .1 += 1;
+                        overlaps.removed.insert(index);
                     }
                 }
-                // if read passes qc, is not a deletion or a refskip then we have a real base
-                if let Some(qpos) = alignment.qpos() {
-                    let base = (record.seq()[qpos] as char).to_ascii_uppercase();
-                    match base {
-                        'A' => {
-                            pileup_struct.a.0 += 1;
-                            if orientation == "+" {
-                                pileup_struct.a.1 .0 += 1;
-                            } else {
-                                pileup_struct.a.1 .1 += 1;
-                            }
-                        }
-                        'T' => {
-                            pileup_struct.t.0 += 1;
-                            if orientation == "+" {
-                                pileup_struct.t.1 .0 += 1;
-                            } else {
-                                pileup_struct.t.1 .1 += 1;
-                            }
-                        }
-                        'C' => {
-                            pileup_struct.c.0 += 1;
-                            if orientation == "+" {
-                                pileup_struct.c.1 .0 += 1;
-                            } else {
-                                pileup_struct.c.1 .1 += 1;
-                            }
-                        }
-                        'G' => {
-                            pileup_struct.g.0 += 1;
-                            if orientation == "+" {
-                                pileup_struct.g.1 .0 += 1;
-                            } else {
-                                pileup_struct.g.1 .1 += 1;
-                            }
-                        }
-                        _ => {}
-                    }
+            }
+        }
+        overlaps
+    }
+
+    /// Count the alleles of every read at a single pileup column, with and without read filtering
+    pub(crate) fn count_column(&self, pileup: &bam::pileup::Pileup, contig: &str) -> ColumnCounts {
+        let context = self.context(contig, pileup.pos());
+        if self.settings.baq {
+            // reads that end before this column will not be seen again
+            let (tid, pos) = (pileup.tid() as i32, pileup.pos() as i64);
+            self.baq_cache
+                .borrow_mut()
+                .retain(|key, (end, _)| key.0 == tid && *end > pos);
+        }
+        // alleles removed at this column by --max-homopolymer
+        let homopolymer_filter = context.as_ref().and_then(|context| {
+            (self.settings.max_homopolymer > 0
+                && context.homopolymer > self.settings.max_homopolymer)
+                .then_some(self.settings.homopolymer_alleles)
+        });
+        let mut counts = ColumnCounts {
+            pileup: Pileup::new(),
+            unfiltered: Pileup::new(),
+            removed: ReadFilterCounts::default(),
+            reads: 0,
+            overlapping_pairs: 0,
+            mate_conflicts: 0,
+            context,
+        };
+        // mates are only compared once both have passed the read filters, so a mate removed by
+        // a filter never conflicts with or replaces its mate
+        let mut reads = Vec::new();
+        for alignment in pileup.alignments() {
+            let record = alignment.record();
+            // Checking the seq isnt empty is necessary as secondary alignments can cause empty seqs
+            if record.seq().is_empty() {
+                counts.removed.add(ReadFilter::EmptySeq);
+                continue;
+            }
+            let forward = record.strand().to_string() == "+";
+            let alleles = read_alleles(&record, &alignment);
+            for allele in &alleles {
+                counts.unfiltered.add(*allele, forward);
+            }
+            let quality = self.base_quality(&record, &alignment, contig);
+            let end_distance = read_end_distance(&record, pileup.pos());
+            if let Some(filter) = self.qc_filter(&record, quality, end_distance) {
+                counts.removed.add(filter);
+                continue;
+            }
+            let mut kept = Vec::with_capacity(alleles.len());
+            for allele in alleles {
+                let is_indel = allele == '-' || allele == '+';
+                if self.settings.no_indel && is_indel {
+                    counts.removed.add(ReadFilter::Indel);
+                    continue;
                 }
-                // Check if insertion
-                if let bam::pileup::Indel::Ins(_len) = alignment.indel() {
-                    if self.no_indel {
+                match homopolymer_filter {
+                    Some(HomopolymerAlleles::All) => {
+                        counts.removed.add(ReadFilter::Homopolymer);
+                        continue;
+                    }
+                    Some(HomopolymerAlleles::Indels) if is_indel => {
+                        counts.removed.add(ReadFilter::Homopolymer);
                         continue;
-                    } else {
-                        pileup_struct.ins.0 += 1;
-                        if orientation == "+" {
-                            pileup_struct.ins.1 .0 += 1;
-                        } else {
-                            pileup_struct.ins.1 .1 += 1;
-                        }
                     }
+                    _ => {}
                 }
+                kept.push(allele);
+            }
+            if kept.is_empty() {
+                continue;
+            }
+            reads.push(ColumnRead {
+                name: record.is_paired().then(|| record.qname().to_vec()),
+                forward,
+                quality,
+                alleles: kept,
+                end_distance,
+                weight: self.read_weight(&record, quality, pileup.pos(), contig),
+            });
+        }
+        let overlaps = if self.settings.dedup_overlaps {
+            self.overlapping_mates(&reads)
+        } else {
+            MateOverlaps::default()
+        };
+        counts.overlapping_pairs = overlaps.pairs;
+        counts.mate_conflicts = overlaps.conflicts;
+        for (index, read) in reads.into_iter().enumerate() {
+            if overlaps.removed.contains(&index) {
+                counts.removed.add(ReadFilter::Overlap);
+                continue;
+            }
+            for allele in read.alleles {
+                match read.weight {
+                    Some(weight) => counts.pileup.add_weighted(allele, read.forward, weight),
+                    None => counts.pileup.add(allele, read.forward),
+                }
+                counts.pileup.add_end_distance(allele, read.end_distance);
+            }
+            counts.reads += 1;
+        }
+        counts
+    }
+
+    /// The column as a fixed difference if it is covered and its consensus is not the reference base
+    fn fixed_difference(
+        &self,
+        pos: u32,
+        depth: u32,
+        counts: &ColumnCounts,
+    ) -> Option<FixedDifference> {
+        let reference = counts.context.as_ref()?.base;
+        if !matches!(reference, 'A' | 'C' | 'G' | 'T')
+            || self.compared_depth(depth, counts) < self.settings.depth_threshold
+            || counts.pileup.get_total() == 0
+        {
+            return None;
+        }
+        // reads with an insertion also carry the base at the column, so an insertion never
+        // replaces the reference base and the consensus is taken from the bases and deletions
+        let consensus = ['A', 'C', 'G', 'T', '-']
+            .into_iter()
+            .map(|allele| (allele, counts.pileup.get_weight(allele)))
+            .filter(|(_, weight)| *weight > 0.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(allele, _)| allele)?;
+        if consensus == reference {
+            return None;
+        }
+        Some(FixedDifference {
+            pos: pos + 1,
+            reference,
+            consensus,
+            proportion: (counts.pileup.get_proportion(consensus) * 10000.0).round() / 10000.0,
+            depth,
+            total: counts.pileup.get_total(),
+        })
+    }
+
+    pub(crate) fn scan(&self, bam: &mut bam::IndexedReader) -> ContigScan {
+        let mut positions_scanned = 0;
+        let mut positions_covered = 0;
+        let mut positions_ambiguous = 0;
+        let mut positions_masked = 0;
+        let mut fixed_differences = self.reference.as_ref().map(|_| Vec::new());
+        let mut reads_removed = ReadFilterCounts::default();
+        let mut overlapping_pairs = 0;
+        let mut mate_conflicts = 0;
+        let mut positions = Vec::new();
+        let mut depths = Vec::new();
+        let keep_depths = self.settings.plot_types.contains(&PlotType::Overview);
+        let header = bam.header().clone();
+        for pileup in pileup_columns(bam, self.settings.max_depth).flatten() {
+            if pileup.pos() < self.start || pileup.pos() >= self.stop {
+                continue;
+            }
+            positions_scanned += 1;
+            if keep_depths {
+                depths.push((pileup.pos() + 1, pileup.depth()));
+            }
+            let contig = std::str::from_utf8(header.tid2name(pileup.tid())).unwrap();
+            let masked = self
+                .mask
+                .as_ref()
+                .is_some_and(|mask| mask.contains(contig, pileup.pos()));
+            if masked {
+                positions_masked += 1;
+                if self.settings.mask_mode == MaskMode::Exclude {
+                    continue;
+                }
+            }
+            let counts = self.count_column(&pileup, contig);
+            reads_removed.merge(&counts.removed);
+            overlapping_pairs += counts.overlapping_pairs;
+            mate_conflicts += counts.mate_conflicts;
+            if self.compared_depth(pileup.depth(), &counts) >= self.settings.depth_threshold {
+                positions_covered += 1;
+            }
+            if let Some(differences) = fixed_differences.as_mut() {
+                differences.extend(self.fixed_difference(pileup.pos(), pileup.depth(), &counts));
             }
 
             // skip processing any if only 1 base present (no ambiguity) at that position
-            if pileup_struct.is_ambiguous() {
-                let pileup_counts = self.filter_base_counts(pileup.pos(), &pileup_struct);
-                if !pileup_counts.is_empty() {
-                    filtered_pileup_counts.extend(pileup_counts);
+            if counts.unfiltered.is_ambiguous() {
+                positions_ambiguous += 1;
+                let mut result = self.evaluate_position(pileup.pos(), pileup.depth(), counts);
+                // masked positions are kept to show they would have been reported
+                let flagged = masked && result.passed;
+                if masked {
+                    result.passed = false;
+                    result.reasons.push(FailReason::Masked);
+                }
+                if result.passed || flagged || self.settings.report_all {
+                    positions.push(result);
                 }
             }
         }
-        filtered_pileup_counts
+        ContigScan {
+            positions_scanned,
+            positions_covered,
+            positions_ambiguous,
+            positions_masked,
+            fixed_differences,
+            reads_removed,
+            overlapping_pairs,
+            mate_conflicts,
+            positions,
+            depths,
+        }
     }
 
-    fn output_bed(&self, tid: &String, pos_to_plot: BTreeMap<u32, BTreeMap<char, f64>>) {
-        let out_name = format!("{}_{}.bed", tid, self.output);
-        let mut file = File::create(out_name).expect("Failed to create file");
+    pub(crate) fn produce_pileup(
+        &self,
+        bam: &mut bam::IndexedReader,
+    ) -> BTreeMap<u32, BTreeMap<char, f64>> {
+        passing_proportions(&self.scan(bam))
+    }
+
+    fn output_bed(&self, tid: &String, pos_to_plot: BTreeMap<u32, BTreeMap<char, f64>>) -> String {
+        let out_name = self
+            .output
+            .contig_path(tid, self.start, self.stop, "ambig", "bed");
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        writeln!(
+            file,
+            "{}",
+            self.provenance.bed_track_line(&sample_name(&out_name))
+        )
+        .expect("Failed to write to file");
+        for line in self.provenance.header_lines() {
+            writeln!(file, "# {}", line).expect("Failed to write to file");
+        }
         for (pos, _) in pos_to_plot {
             writeln!(file, "{}\t{}\t{}\t{}", tid, pos, pos + 1, tid)
                 .expect("Failed to write to file");
         }
+        out_name
+    }
+
+    /// Write every ambiguous candidate with its filter outcome and the reads each read filter removed
+    fn output_report(&self, tid: &str, scan: &ContigScan) -> String {
+        let out_name = self
+            .output
+            .contig_path(tid, self.start, self.stop, "report", "tsv");
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        for line in self.provenance.header_lines() {
+            writeln!(file, "# {}", line).expect("Failed to write to file");
+        }
+        writeln!(
+            file,
+            "pos\tdepth\ttotal\tmajor\tminor_proportion\tstatus\tstrand_failed\talleles\tremoved_empty_seq\tremoved_base_quality\tremoved_map_quality\tremoved_indel\tremoved_read_end\tremoved_homopolymer\tremoved_overlap\tweighted_alleles\tmean_end_distance\tread_pos_rank_sum\thomopolymer\tkmer\tgc\tsecond_allele\tsecond_proportion\tallele_count\tfiltered_depth\tmate_conflicts"
+        )
+        .expect("Failed to write to file");
+        for result in &scan.positions {
+            let status = if result.passed {
+                "PASS".to_string()
+            } else {
+                result
+                    .reasons
+                    .iter()
+                    .map(|reason| reason.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(",")
+            };
+            let strand_failed: String = result.strand_failed.iter().collect();
+            let alleles = ['A', 'C', 'G', 'T', '-', '+']
+                .iter()
+                .filter(|allele| result.pileup.get_base_count(**allele) > 0)
+                .map(|allele| format!("{}:{}", allele, result.pileup.get_base_count(*allele)))
+                .collect::<Vec<String>>()
+                .join(",");
+            let weighted_alleles = ['A', 'C', 'G', 'T', '-', '+']
+                .iter()
+                .filter(|allele| result.pileup.weighted.contains_key(*allele))
+                .map(|allele| format!("{}:{:.2}", allele, result.pileup.get_weight(*allele)))
+                .collect::<Vec<String>>()
+                .join(",");
+            let mean_end_distances = ['A', 'C', 'G', 'T', '-', '+']
+                .iter()
+                .filter_map(|allele| {
+                    result
+                        .pileup
+                        .mean_end_distance(*allele)
+                        .map(|mean| format!("{}:{:.1}", allele, mean))
+                })
+                .collect::<Vec<String>>()
+                .join(",");
+            let rank_sums = ['A', 'C', 'G', 'T', '-', '+']
+                .iter()
+                .filter_map(|allele| {
+                    result
+                        .pileup
+                        .read_pos_rank_sum(*allele, result.major)
+                        .map(|z| format!("{}:{:.3}", allele, z))
+                })
+                .collect::<Vec<String>>()
+                .join(",");
+            let (second_allele, second_proportion) = match result.second_allele() {
+                Some((allele, proportion)) => (allele.to_string(), format!("{:.4}", proportion)),
+                None => (".".to_string(), ".".to_string()),
+            };
+            let (homopolymer, kmer, gc) = match &result.context {
+                Some(context) => (
+                    context.homopolymer.to_string(),
+                    context.kmer.clone(),
+                    format!("{:.4}", context.gc),
+                ),
+                None => (".".to_string(), ".".to_string(), ".".to_string()),
+            };
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{:.4}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                result.pos,
+                result.depth,
+                result.pileup.get_total(),
+                result.major,
+                result.minor_proportion,
+                status,
+                if strand_failed.is_empty() {
+                    "."
+                } else {
+                    &strand_failed
+                },
+                if alleles.is_empty() { "." } else { &alleles },
+                result.removed.empty_seq,
+                result.removed.base_quality,
+                result.removed.map_quality,
+                result.removed.indel,
+                result.removed.read_end,
+                result.removed.homopolymer,
+                result.removed.overlap,
+                if weighted_alleles.is_empty() {
+                    "."
+                } else {
+                    &weighted_alleles
+                },
+                if mean_end_distances.is_empty() {
+                    "."
+                } else {
+                    &mean_end_distances
+                },
+                if rank_sums.is_empty() {
+                    "."
+                } else {
+                    &rank_sums
+                },
+                homopolymer,
+                kmer,
+                gc,
+                second_allele,
+                second_proportion,
+                result.allele_count,
+                result.filtered_depth,
+                result.mate_conflicts
+            )
+            .expect("Failed to write to file");
+        }
+        out_name
+    }
+
+    /// Allele counts and raw depth at a single site, None when no read covers it
+    pub(crate) fn genotype_site(
+        &self,
+        bam: &mut bam::IndexedReader,
+        site: &Site,
+    ) -> Option<(u32, ColumnCounts)> {
+        let tid = bam.header().tid(site.contig.as_bytes())?;
+        let pos = site.pos - 1;
+        bam.fetch((tid, pos, pos + 1))
+            .expect("Failed to fetch site");
+        for pileup in pileup_columns(bam, self.settings.max_depth).flatten() {
+            if pileup.pos() == pos {
+                let counts = self.count_column(&pileup, &site.contig);
+                return Some((pileup.depth(), counts));
+            }
+        }
+        None
+    }
+
+    /// Write the allele counts at every --sites position in the region, including those no read covers
+    fn output_sites(&self, bam: &mut bam::IndexedReader, sites: &[Site]) -> String {
+        let out_name = self.output.run_path("sites", "tsv");
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        for line in self.provenance.header_lines() {
+            writeln!(file, "# {}", line).expect("Failed to write to file");
+        }
+        writeln!(
+            file,
+            "contig\tpos\tid\tref\talt\tdepth\tfiltered_depth\ttotal\tmajor\talleles\tproportions\talt_count\talt_proportion"
+        )
+        .expect("Failed to write to file");
+        for site in sites {
+            let pos = site.pos - 1;
+            if self.chrom.is_some_and(|chrom| chrom != site.contig)
+                || pos < self.start
+                || pos >= self.stop
+            {
+                continue;
+            }
+            let (depth, filtered_depth, pileup) = match self.genotype_site(bam, site) {
+                Some((depth, counts)) => (depth, counts.reads, counts.pileup),
+                None => (0, 0, Pileup::new()),
+            };
+            let reference = site.reference.clone().unwrap_or_else(|| {
+                self.reference
+                    .as_ref()
+                    .and_then(|reference| reference.base(&site.contig, pos))
+                    .map_or(".".to_string(), |base| base.to_string())
+            });
+            let covered = pileup.get_total() > 0;
+            let present: Vec<char> = ['A', 'C', 'G', 'T', '-', '+']
+                .into_iter()
+                .filter(|allele| pileup.get_base_count(*allele) > 0)
+                .collect();
+            let alleles = present
+                .iter()
+                .map(|allele| format!("{}:{}", allele, pileup.get_base_count(*allele)))
+                .collect::<Vec<String>>()
+                .join(",");
+            let proportions = present
+                .iter()
+                .map(|allele| format!("{}:{:.4}", allele, pileup.get_proportion(*allele)))
+                .collect::<Vec<String>>()
+                .join(",");
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                site.contig,
+                site.pos,
+                site.id,
+                reference,
+                site.alt.map_or(".".to_string(), |alt| alt.to_string()),
+                depth,
+                filtered_depth,
+                pileup.get_total(),
+                if covered {
+                    pileup.get_major_variant().to_string()
+                } else {
+                    ".".to_string()
+                },
+                if covered { &alleles } else { "." },
+                if covered { &proportions } else { "." },
+                site.alt.map_or(".".to_string(), |alt| pileup
+                    .get_base_count(alt)
+                    .to_string()),
+                match site.alt {
+                    Some(alt) if covered => format!("{:.4}", pileup.get_proportion(alt)),
+                    _ => ".".to_string(),
+                }
+            )
+            .expect("Failed to write to file");
+        }
+        out_name
+    }
+
+    /// Write every position whose consensus differs from the reference
+    fn output_fixed_differences(&self, tid: &str, differences: &[FixedDifference]) -> String {
+        let out_name = self
+            .output
+            .contig_path(tid, self.start, self.stop, "fixed", "tsv");
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        for line in self.provenance.header_lines() {
+            writeln!(file, "# {}", line).expect("Failed to write to file");
+        }
+        writeln!(
+            file,
+            "pos\treference\tconsensus\tconsensus_proportion\tdepth\ttotal"
+        )
+        .expect("Failed to write to file");
+        for difference in differences {
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{:.4}\t{}\t{}",
+                difference.pos,
+                difference.reference,
+                difference.consensus,
+                difference.proportion,
+                difference.depth,
+                difference.total
+            )
+            .expect("Failed to write to file");
+        }
+        out_name
+    }
+
+    /// Write one row of summary figures per contig
+    fn output_summary(&self, summaries: &[(String, ContigSummary)]) -> String {
+        let out_name = self.output.run_path("ambig.summary", "tsv");
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        for line in self.provenance.header_lines() {
+            writeln!(file, "# {}", line).expect("Failed to write to file");
+        }
+        writeln!(
+            file,
+            "contig\tpositions_scanned\tpositions_covered\tpositions_ambiguous\tpositions_masked\tpositions_passed\tpositions_multiallelic\tfixed_differences\tpassed_per_kb\tmedian_minor_proportion\toverlapping_pairs\tmate_conflicts\tremoved_empty_seq\tremoved_base_quality\tremoved_map_quality\tremoved_indel\tremoved_read_end\tremoved_homopolymer\tremoved_overlap"
+        )
+        .expect("Failed to write to file");
+        for (tid, summary) in summaries {
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                tid,
+                summary.positions_scanned,
+                summary.positions_covered,
+                summary.positions_ambiguous,
+                summary.positions_masked,
+                summary.positions_passed,
+                summary.positions_multiallelic,
+                summary
+                    .fixed_differences
+                    .map_or(".".to_string(), |count| count.to_string()),
+                summary.passed_per_kb,
+                summary
+                    .median_minor_proportion
+                    .map_or(".".to_string(), |median| median.to_string()),
+                summary.overlapping_pairs,
+                summary.mate_conflicts,
+                summary.reads_removed.empty_seq,
+                summary.reads_removed.base_quality,
+                summary.reads_removed.map_quality,
+                summary.reads_removed.indel,
+                summary.reads_removed.read_end,
+                summary.reads_removed.homopolymer,
+                summary.reads_removed.overlap
+            )
+            .expect("Failed to write to file");
+        }
+        out_name
     }
 
-    pub fn run(&self) {
+    pub fn run(&self) -> Result<(), String> {
         let mut bam = read_bam(self.input);
         let tids = get_tids(self.chrom, Some(bam.header()));
         println!("Tids: {:?}", tids);
+        let mut contig_outputs = plot_outputs(&["ambig", "strand", "overview"]);
+        contig_outputs.extend([("ambig", "bed"), ("report", "tsv"), ("fixed", "tsv")]);
+        self.output.check_free(
+            &tids,
+            &contig_outputs,
+            &[
+                ("sites", "tsv"),
+                ("ambig.summary", "tsv"),
+                ("ambig", "json"),
+                ("ambig.manifest", "json"),
+            ],
+        )?;
+        let mut outputs = Vec::new();
+        let mut json = AmbigJson::new(&self.provenance);
+        let mut summaries = Vec::new();
+        #[cfg_attr(
+            not(any(feature = "plot", feature = "native-plot")),
+            allow(unused_variables)
+        )]
+        let plot_types: Vec<PlotType> = self
+            .settings
+            .plot_types
+            .iter()
+            .filter(|plot_type| plot_supported(plot_type.as_str(), **plot_type == PlotType::Bar))
+            .cloned()
+            .collect();
+        #[cfg(feature = "plot")]
+        let features = match &self.settings.annotation {
+            Some(path) => read_annotation(path)?,
+            None => Vec::new(),
+        };
+        // read before the scan so an invalid file stops the run early
+        let sites = self.settings.sites.as_deref().map(read_sites).transpose()?;
         // run for each chromosome
         for tid in &tids {
             println!("Processing Tid: {}", tid);
             bam.fetch((tid, self.start, self.stop))
                 .expect("Failed to fetch region");
-            let filtered_pileup_counts = self.produce_pileup(&mut bam);
-            self.plot(&filtered_pileup_counts, tid);
+            let scan = self.scan(&mut bam);
+            let filtered_pileup_counts = passing_proportions(&scan);
+            #[cfg(any(feature = "plot", feature = "native-plot"))]
+            {
+                let passed = scan.passed();
+                let pages = self.pages(&passed);
+                for (index, page) in pages.iter().enumerate() {
+                    if plot_types.contains(&PlotType::Bar) {
+                        outputs.push(self.plot(page, tid, index + 1, pages.len()));
+                    }
+                    #[cfg(feature = "plot")]
+                    if plot_types.contains(&PlotType::Strand) {
+                        outputs.push(self.plot_strand(page, tid, index + 1, pages.len()));
+                    }
+                }
+            }
+            #[cfg(feature = "plot")]
+            if plot_types.contains(&PlotType::Overview) {
+                outputs.push(self.plot_overview(&scan, &features, tid));
+            }
             //self.output_tsv(&pos_to_plot);
-            if self.bed {
-                self.output_bed(tid, filtered_pileup_counts)
+            if self.settings.bed {
+                outputs.push(self.output_bed(tid, filtered_pileup_counts));
+            }
+            if self.settings.report_all {
+                outputs.push(self.output_report(tid, &scan));
+            }
+            if let Some(differences) = &scan.fixed_differences {
+                outputs.push(self.output_fixed_differences(tid, differences));
             }
+            if self.settings.json {
+                json.add_contig(tid, &scan);
+            }
+            summaries.push((tid.to_string(), scan.summary()));
+        }
+        println!("Summary:");
+        for (tid, summary) in &summaries {
+            println!(
+                "  {}: scanned={} covered={} ambiguous={} masked={} passed={} multiallelic={} fixed={} passed/kb={} median-minor={} overlapping-pairs={} mate-conflicts={} removed: empty-seq={} min-BQ={} min-MQ={} indel={} read-end={} homopolymer={} overlap={}",
+                tid,
+                summary.positions_scanned,
+                summary.positions_covered,
+                summary.positions_ambiguous,
+                summary.positions_masked,
+                summary.positions_passed,
+                summary.positions_multiallelic,
+                summary
+                    .fixed_differences
+                    .map_or("NA".to_string(), |count| count.to_string()),
+                summary.passed_per_kb,
+                summary
+                    .median_minor_proportion
+                    .map_or("NA".to_string(), |median| median.to_string()),
+                summary.overlapping_pairs,
+                summary.mate_conflicts,
+                summary.reads_removed.empty_seq,
+                summary.reads_removed.base_quality,
+                summary.reads_removed.map_quality,
+                summary.reads_removed.indel,
+                summary.reads_removed.read_end,
+                summary.reads_removed.homopolymer,
+                summary.reads_removed.overlap
+            );
+        }
+        outputs.push(self.output_summary(&summaries));
+        if let Some(sites) = &sites {
+            outputs.push(self.output_sites(&mut bam, sites));
+        }
+        if self.settings.json {
+            let out_name = self.output.run_path("ambig", "json");
+            json.write(&out_name);
+            outputs.push(out_name);
         }
+        self.provenance
+            .write_manifest(&self.output.run_path("ambig.manifest", "json"), &outputs);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use super::*;
 
     #[test]
@@ -457,16 +2150,18 @@ mod tests {
             Some("chr1"),
             Some(4),
             Some(4),
-            true,
-            0.5,
-            false,
-            "".to_string(),
-            1,
-            1,
-            1,
-            0.0,
-            false,
-        );
+            Settings {
+                threshold: 0.5,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: true,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
         assert_eq!(pos.len(), 0);
     }
@@ -479,16 +2174,18 @@ mod tests {
             Some("chr1"),
             Some(1),
             Some(1),
-            true,
-            0.2,
-            false,
-            "".to_string(),
-            1,
-            1,
-            1,
-            0.0,
-            false,
-        );
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: true,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
 
         let expected_pos = {
@@ -512,16 +2209,18 @@ mod tests {
             Some("chr1"),
             Some(5),
             Some(5),
-            false,
-            0.2,
-            false,
-            "".to_string(),
-            1,
-            1,
-            1,
-            0.0,
-            false,
-        );
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
 
         let expected_pos = {
@@ -545,16 +2244,18 @@ mod tests {
             Some("chr1"),
             Some(7),
             Some(8),
-            false,
-            0.2,
-            false,
-            "".to_string(),
-            1,
-            1,
-            1,
-            0.0,
-            false,
-        );
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
 
         let expected_pos = {
@@ -578,16 +2279,18 @@ mod tests {
             Some("chr1"),
             Some(2),
             Some(2),
-            false,
-            0.2,
-            false,
-            "".to_string(),
-            10,
-            1,
-            1,
-            0.0,
-            false,
-        );
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 10,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
         assert_eq!(pos.len(), 0);
     }
@@ -600,16 +2303,18 @@ mod tests {
             Some("chr1"),
             Some(3),
             Some(3),
-            false,
-            0.2,
-            false,
-            "".to_string(),
-            1,
-            20,
-            1,
-            0.0,
-            false,
-        );
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 20,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
         assert_eq!(pos.len(), 0);
     }
@@ -622,16 +2327,18 @@ mod tests {
             Some("chr1"),
             Some(9),
             Some(9),
-            false,
-            0.2,
-            false,
-            "".to_string(),
-            1,
-            1,
-            1,
-            0.1,
-            false,
-        );
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.1,
+                no_indel: false,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
         let pos = ambig.produce_pileup(&mut bam);
         let expected_pos = {
             let mut expected_pos = BTreeMap::new();
@@ -645,4 +2352,783 @@ mod tests {
         };
         assert_eq!(pos, expected_pos);
     }
+
+    #[test]
+    fn test_scan_report_all_low_base_quality() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            Some(2),
+            Some(2),
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 10,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                report_all: true,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let scan = ambig.scan(&mut bam);
+        assert_eq!(scan.positions_ambiguous, 1);
+        assert_eq!(scan.positions.len(), 1);
+        let result = &scan.positions[0];
+        assert_eq!(result.pos, 2);
+        assert!(!result.passed);
+        assert!(result.reasons.contains(&FailReason::ReadFilters));
+        assert!(result.removed.base_quality > 0);
+        assert_eq!(result.removed.map_quality, 0);
+    }
+
+    #[test]
+    fn test_scan_report_all_depth() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            Some(1),
+            Some(1),
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1000,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                report_all: true,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let scan = ambig.scan(&mut bam);
+        assert_eq!(scan.positions.len(), 1);
+        assert_eq!(scan.positions[0].reasons, vec![FailReason::Depth]);
+        assert!(passing_proportions(&scan).is_empty());
+    }
+
+    #[test]
+    fn test_scan_weighting() {
+        // position 2 has eight G reads at Q37 and four T reads at Q9, three G reads are MAPQ 10
+        let scan_with = |weighting| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                Some(2),
+                Some(2),
+                Settings {
+                    threshold: 0.1,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    weighting,
+                    ..Settings::default()
+                },
+            )
+            .positions
+            .remove(0)
+        };
+        let raw = scan_with(Weighting::None);
+        assert_eq!(raw.proportions[&'T'], 0.3333);
+        assert!(!raw.pileup.is_weighted());
+
+        let weighted = scan_with(Weighting::Bq);
+        assert_eq!(weighted.proportions[&'T'], 0.3042);
+        assert_eq!(weighted.pileup.get_base_count('T'), 4);
+        assert_eq!(weighted.pileup.get_total(), 12);
+
+        let weighted = scan_with(Weighting::BqMq);
+        assert_eq!(weighted.proportions[&'T'], 0.3123);
+        assert_eq!(weighted.major, 'G');
+    }
+
+    #[test]
+    fn test_scan_weighting_deletion() {
+        // two reads delete position 5 between Q10 bases, two read an A at Q40
+        let dir = tempfile::tempdir().unwrap();
+        let mut bam = test_bam(
+            dir.path(),
+            &[
+                b"del1\t0\tchr1\t1\t60\t4M1D5M\t*\t0\t0\tAAAAAAAAA\tIII++IIII",
+                b"del2\t0\tchr1\t1\t60\t4M1D5M\t*\t0\t0\tAAAAAAAAA\tIII++IIII",
+                b"base1\t0\tchr1\t1\t60\t10M\t*\t0\t0\tAAAAAAAAAA\tIIIIIIIIII",
+                b"base2\t0\tchr1\t1\t60\t10M\t*\t0\t0\tAAAAAAAAAA\tIIIIIIIIII",
+            ],
+        );
+        let result = scan_chr1(
+            &mut bam,
+            Some(5),
+            Some(5),
+            Settings {
+                threshold: 0.1,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                weighting: Weighting::Bq,
+                ..Settings::default()
+            },
+        )
+        .positions
+        .remove(0);
+        // each deletion is weighted by its Q10 flanking bases, 1.8 / (1.8 + 1.9998)
+        assert_eq!(result.proportions[&'-'], 0.4737);
+        assert_eq!(result.pileup.get_base_count('-'), 2);
+    }
+
+    #[test]
+    fn test_scan_read_end_distance() {
+        // every read is aligned from position 1 to 10, so position 2 is one base from the start
+        let scan_with = |read_end_distance| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                Some(2),
+                Some(2),
+                Settings {
+                    threshold: 0.1,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    read_end_distance,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+            .positions
+            .remove(0)
+        };
+        let kept = scan_with(1);
+        assert_eq!(kept.removed.read_end, 0);
+        assert_eq!(kept.pileup.mean_end_distance('G'), Some(1.0));
+        // every read has the same distance so there is no rank difference to test
+        assert_eq!(kept.pileup.read_pos_rank_sum('T', 'G'), None);
+
+        let removed = scan_with(2);
+        assert_eq!(removed.removed.read_end, 12);
+        assert_eq!(removed.pileup.get_total(), 0);
+    }
+
+    #[test]
+    fn test_scan_homopolymer() {
+        // the reference has six Gs from position 2 to 7, reads 1, 5 and 6 delete position 5
+        let scan_with = |homopolymer_alleles| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                Some(5),
+                Some(5),
+                Settings {
+                    threshold: 0.1,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    reference: Some("test-data/ambig.fa".to_string()),
+                    max_homopolymer: 5,
+                    homopolymer_alleles,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+            .positions
+            .remove(0)
+        };
+        let indels = scan_with(HomopolymerAlleles::Indels);
+        let context = indels.context.as_ref().unwrap();
+        assert_eq!(context.homopolymer, 6);
+        assert_eq!(context.kmer, "AGGGGGGCGG");
+        assert_eq!(indels.removed.homopolymer, 3);
+        assert_eq!(indels.pileup.get_base_count('-'), 0);
+        assert_eq!(indels.pileup.get_base_count('G'), 9);
+
+        let all = scan_with(HomopolymerAlleles::All);
+        assert_eq!(all.removed.homopolymer, 12);
+        assert_eq!(all.pileup.get_total(), 0);
+        // a reference that cannot be read is an error rather than a panic
+        let missing = Ambig::new(
+            "",
+            Some("chr1"),
+            None,
+            None,
+            Settings {
+                reference: Some("test-data/missing.fa".to_string()),
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        );
+        assert!(missing.is_err_and(|e| e.contains("test-data/missing.fa")));
+    }
+
+    #[test]
+    fn test_scan_mask() {
+        let dir = tempfile::tempdir().unwrap();
+        let mask = dir.path().join("mask.bed");
+        std::fs::write(&mask, "chr1\t1\t2\n").unwrap();
+        let scan_with = |mask_mode| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                None,
+                None,
+                Settings {
+                    threshold: 0.1,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    mask: Some(mask.to_str().unwrap().to_string()),
+                    mask_mode,
+                    ..Settings::default()
+                },
+            )
+        };
+        let excluded = scan_with(MaskMode::Exclude);
+        assert_eq!(excluded.positions_masked, 1);
+        assert!(excluded.positions.iter().all(|result| result.pos != 2));
+
+        let flagged = scan_with(MaskMode::Flag);
+        assert_eq!(flagged.positions_masked, 1);
+        let result = flagged
+            .positions
+            .iter()
+            .find(|result| result.pos == 2)
+            .unwrap();
+        assert!(!result.passed);
+        assert_eq!(result.reasons, vec![FailReason::Masked]);
+        assert!(!passing_proportions(&flagged).contains_key(&2));
+    }
+
+    #[test]
+    fn test_genotype_site() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            None,
+            None,
+            Settings {
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let site = |contig: &str, pos| Site {
+            contig: contig.to_string(),
+            pos,
+            id: ".".to_string(),
+            reference: None,
+            alt: Some('T'),
+        };
+        let (depth, counts) = ambig.genotype_site(&mut bam, &site("chr1", 2)).unwrap();
+        assert_eq!(depth, 12);
+        assert_eq!(counts.reads, 12);
+        assert_eq!(counts.pileup.get_base_count('T'), 4);
+        assert_eq!(counts.pileup.get_base_count('G'), 8);
+        // no reads cover position 500 and chr2 is not in the BAM
+        assert!(ambig.genotype_site(&mut bam, &site("chr1", 500)).is_none());
+        assert!(ambig.genotype_site(&mut bam, &site("chr2", 2)).is_none());
+    }
+
+    #[test]
+    fn test_scan_threshold_mode() {
+        // position 9 has five C, four G and three T reads
+        let scan_with = |threshold, threshold_mode| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                Some(9),
+                Some(9),
+                Settings {
+                    threshold,
+                    threshold_mode,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+            .positions
+            .remove(0)
+        };
+        let result = scan_with(0.2, ThresholdMode::Total);
+        assert_eq!(result.major, 'C');
+        assert_eq!(result.second_allele(), Some(('G', 0.3333)));
+        assert_eq!(result.allele_count, 3);
+        assert!(result.is_multiallelic());
+
+        // the minor alleles sum to 0.58 but neither is above 0.35 on its own
+        assert!(scan_with(0.35, ThresholdMode::Total).passed);
+        let result = scan_with(0.35, ThresholdMode::PerAllele);
+        assert!(!result.passed);
+        assert_eq!(result.reasons, vec![FailReason::Threshold]);
+        assert_eq!(result.allele_count, 1);
+        assert!(scan_with(0.3, ThresholdMode::PerAllele).passed);
+    }
+
+    #[test]
+    fn test_scan_depth_mode() {
+        // position 2 has eight G reads at Q37 and four T reads at Q9
+        let scan_with = |depth_mode| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                Some(2),
+                Some(2),
+                Settings {
+                    base_quality_threshold: 30,
+                    map_quality_threshold: 1,
+                    depth_threshold: 10,
+                    depth_mode,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+        };
+        let scan = scan_with(DepthMode::Raw);
+        assert_eq!(scan.positions_covered, 1);
+        let result = &scan.positions[0];
+        assert_eq!((result.depth, result.filtered_depth), (12, 8));
+        assert!(!result.reasons.contains(&FailReason::Depth));
+
+        let scan = scan_with(DepthMode::Filtered);
+        assert_eq!(scan.positions_covered, 0);
+        assert!(scan.positions[0].reasons.contains(&FailReason::Depth));
+    }
+
+    /// Write an indexed BAM of overlapping read pairs, all covering positions 1 to 10 of chr1
+    fn overlap_bam(dir: &Path) -> bam::IndexedReader {
+        test_bam(
+            dir,
+            &[
+                // mates agreeing at position 5
+                b"pair1\t99\tchr1\t1\t60\t10M\t=\t1\t10\tAAAAAAAAAA\tIIIIIIIIII",
+                b"pair1\t147\tchr1\t1\t60\t10M\t=\t1\t-10\tAAAAAAAAAA\tIIIIIIIIII",
+                // mates disagreeing at position 5, the C at Q20 and the A at Q40
+                b"pair2\t99\tchr1\t1\t60\t10M\t=\t1\t10\tAAAAAAAAAA\tIIIIIIIIII",
+                b"pair2\t147\tchr1\t1\t60\t10M\t=\t1\t-10\tAAAACAAAAA\t5555555555",
+                b"single\t0\tchr1\t1\t60\t10M\t*\t0\t0\tAAAACAAAAA\tIIIIIIIIII",
+            ],
+        )
+    }
+
+    /// Write SAM records sorted by position to an indexed BAM on a 1kb chr1, inside a
+    /// directory owned by the test
+    fn test_bam(dir: &Path, records: &[&[u8]]) -> bam::IndexedReader {
+        let path = dir.join("test.bam");
+        let mut header = bam::Header::new();
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 1000),
+        );
+        let header_view = bam::HeaderView::from_header(&header);
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+        for record in records {
+            writer
+                .write(&bam::Record::from_sam(&header_view, record).unwrap())
+                .unwrap();
+        }
+        drop(writer);
+        bam::index::build(&path, None, bam::index::Type::Bai, 1).unwrap();
+        bam::IndexedReader::from_path(&path).unwrap()
+    }
+
+    /// Scan chr1 between 1-based `start` and `stop` with the given settings
+    fn scan_chr1(
+        bam: &mut bam::IndexedReader,
+        start: Option<u32>,
+        stop: Option<u32>,
+        settings: Settings,
+    ) -> ContigScan {
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            start,
+            stop,
+            settings,
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        bam.fetch("chr1").unwrap();
+        ambig.scan(bam)
+    }
+
+    #[test]
+    fn test_scan_dedup_overlaps() {
+        let scan_with = |dedup_overlaps| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut bam = overlap_bam(dir.path());
+            scan_chr1(
+                &mut bam,
+                Some(5),
+                Some(5),
+                Settings {
+                    threshold: 0.2,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    dedup_overlaps,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+            .positions
+            .remove(0)
+        };
+        let result = scan_with(false);
+        assert_eq!(result.pileup.get_base_count('A'), 3);
+        assert_eq!(result.pileup.get_base_count('C'), 2);
+        assert_eq!(result.filtered_depth, 5);
+        assert_eq!(result.mate_conflicts, 0);
+
+        // one base per pair, the higher quality A of the disagreeing pair is kept
+        let result = scan_with(true);
+        assert_eq!(result.pileup.get_base_count('A'), 2);
+        assert_eq!(result.pileup.get_base_count('C'), 1);
+        assert_eq!(result.removed.overlap, 2);
+        assert_eq!((result.depth, result.filtered_depth), (5, 3));
+    }
+
+    #[test]
+    fn test_scan_mate_conflict() {
+        let scan_with = |mate_conflict| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut bam = overlap_bam(dir.path());
+            scan_chr1(
+                &mut bam,
+                Some(5),
+                Some(5),
+                Settings {
+                    threshold: 0.2,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    dedup_overlaps: true,
+                    mate_conflict,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+        };
+        let scan = scan_with(MateConflict::Best);
+        assert_eq!((scan.overlapping_pairs, scan.mate_conflicts), (2, 1));
+        assert_eq!(scan.positions[0].mate_conflicts, 1);
+        assert_eq!(scan.positions[0].pileup.get_base_count('A'), 2);
+
+        // neither mate of the disagreeing pair is counted
+        let scan = scan_with(MateConflict::Discard);
+        assert_eq!((scan.overlapping_pairs, scan.mate_conflicts), (2, 1));
+        let result = &scan.positions[0];
+        assert_eq!(result.pileup.get_base_count('A'), 1);
+        assert_eq!(result.pileup.get_base_count('C'), 1);
+        assert_eq!(result.removed.overlap, 3);
+        assert_eq!(result.filtered_depth, 2);
+    }
+
+    #[test]
+    fn test_scan_mate_below_min_mq() {
+        // the second mate reads a C at Q40 but maps at MAPQ 5, below --min-MQ
+        let scan_with = |mate_conflict| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut bam = test_bam(
+                dir.path(),
+                &[
+                    b"pair1\t99\tchr1\t1\t60\t10M\t=\t1\t10\tAAAAAAAAAA\t??????????",
+                    b"pair1\t147\tchr1\t1\t5\t10M\t=\t1\t-10\tAAAACAAAAA\tIIIIIIIIII",
+                    b"single\t0\tchr1\t1\t60\t10M\t*\t0\t0\tAAAACAAAAA\tIIIIIIIIII",
+                ],
+            );
+            scan_chr1(
+                &mut bam,
+                Some(5),
+                Some(5),
+                Settings {
+                    threshold: 0.2,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 20,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    dedup_overlaps: true,
+                    mate_conflict,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+            .positions
+            .remove(0)
+        };
+        // the failing mate is removed by the filter and its good mate is still counted
+        for mate_conflict in [MateConflict::Best, MateConflict::Discard] {
+            let result = scan_with(mate_conflict);
+            assert_eq!(result.pileup.get_base_count('A'), 1);
+            assert_eq!(result.pileup.get_base_count('C'), 1);
+            assert_eq!(result.removed.map_quality, 1);
+            assert_eq!(result.removed.overlap, 0);
+            assert_eq!(result.mate_conflicts, 0);
+        }
+    }
+
+    #[test]
+    fn test_scan_max_depth_and_baq() {
+        let scan_with = |max_depth, baq| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                None,
+                None,
+                Settings {
+                    base_quality_threshold: 30,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    max_depth,
+                    baq,
+                    reference: Some("test-data/ambig.fa".to_string()),
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+        };
+        let scan = scan_with(0, false);
+        assert_eq!(scan.positions[0].depth, 12);
+        assert_eq!(scan.reads_removed.base_quality, 4);
+        // htslib stops adding reads to a column once it holds max_depth
+        assert_eq!(scan_with(5, false).positions[0].depth, 5);
+        // BAQ only ever lowers base qualities
+        let scan = scan_with(0, true);
+        assert!(scan.reads_removed.base_quality > 4);
+    }
+
+    #[test]
+    fn test_scan_fixed_differences_and_af_band() {
+        let scan_with = |min_af, max_af| {
+            let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+            scan_chr1(
+                &mut bam,
+                None,
+                None,
+                Settings {
+                    threshold: 0.1,
+                    base_quality_threshold: 1,
+                    map_quality_threshold: 1,
+                    depth_threshold: 1,
+                    strand_bias_threshold: 0.0,
+                    reference: Some("test-data/ambig.fa".to_string()),
+                    min_af,
+                    max_af,
+                    report_all: true,
+                    ..Settings::default()
+                },
+            )
+        };
+        // every read has a G where the reference has C at position 8
+        let scan = scan_with(0.0, 1.0);
+        let differences = scan.fixed_differences.unwrap();
+        assert_eq!(differences.len(), 2);
+        assert_eq!(
+            (
+                differences[0].pos,
+                differences[0].reference,
+                differences[0].consensus
+            ),
+            (8, 'C', 'G')
+        );
+        assert_eq!(differences[0].proportion, 1.0);
+        assert_eq!((differences[1].pos, differences[1].consensus), (9, 'C'));
+
+        // position 1 has a reference A with G at 0.42
+        let position = |scan: &ContigScan, pos| {
+            scan.positions
+                .iter()
+                .find(|result| result.pos == pos)
+                .unwrap()
+                .clone()
+        };
+        assert!(position(&scan_with(0.4, 0.5), 1).passed);
+        let result = position(&scan_with(0.45, 0.95), 1);
+        assert_eq!(result.reasons, vec![FailReason::AlleleFrequency]);
+    }
+
+    #[test]
+    fn test_fixed_difference_insertion() {
+        // every read carries the reference A at position 5 followed by an insertion
+        let dir = tempfile::tempdir().unwrap();
+        let fasta = dir.path().join("ref.fa");
+        std::fs::write(&fasta, ">chr1\nAAAAAAAAAA\n").unwrap();
+        let mut bam = test_bam(
+            dir.path(),
+            &[
+                b"ins1\t0\tchr1\t1\t60\t5M2I5M\t*\t0\t0\tAAAAATTAAAAA\tIIIIIIIIIIII",
+                b"ins2\t16\tchr1\t1\t60\t5M2I5M\t*\t0\t0\tAAAAATTAAAAA\tIIIIIIIIIIII",
+            ],
+        );
+        let scan = scan_chr1(
+            &mut bam,
+            None,
+            None,
+            Settings {
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                reference: Some(fasta.to_str().unwrap().to_string()),
+                ..Settings::default()
+            },
+        );
+        assert_eq!(scan.fixed_differences, Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_rank_sum_z() {
+        let z = rank_sum_z(&[1, 2], &[3, 4, 5]).unwrap();
+        assert!((z + 3.0_f64.sqrt()).abs() < 1e-9);
+        assert!(rank_sum_z(&[4, 5], &[1, 2, 3]).unwrap() > 0.0);
+        assert_eq!(rank_sum_z(&[], &[1, 2]), None);
+        assert_eq!(rank_sum_z(&[3, 3], &[3]), None);
+    }
+
+    #[test]
+    fn test_scan_summary() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            None,
+            None,
+            Settings {
+                threshold: 0.1,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.1,
+                no_indel: false,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let summary = ambig.scan(&mut bam).summary();
+        assert_eq!(summary.positions_scanned, 10);
+        assert_eq!(summary.positions_covered, 10);
+        assert_eq!(summary.positions_ambiguous, 6);
+        assert_eq!(summary.positions_passed, 3);
+        assert_eq!(summary.passed_per_kb, 300.0);
+        assert_eq!(summary.median_minor_proportion, Some(0.3333));
+        assert_eq!(summary.reads_removed, ReadFilterCounts::default());
+    }
+
+    #[test]
+    #[cfg(all(feature = "plot", any(feature = "html", not(feature = "native-plot"))))]
+    fn test_bar_traces_counts() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            Some(1),
+            Some(1),
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: true,
+                plot_values: PlotValues::Counts,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let scan = ambig.scan(&mut bam);
+        let traces = ambig.bar_traces(&scan.passed());
+        let a = serde_json::to_value(&traces[0]).unwrap();
+        let g = serde_json::to_value(&traces[2]).unwrap();
+        assert_eq!(a["y"], serde_json::json!([7.0]));
+        assert_eq!(g["y"], serde_json::json!([5.0]));
+        assert_eq!(g["text"], serde_json::json!(["5"]));
+    }
+
+    #[test]
+    #[cfg(feature = "plot")]
+    fn test_strand_traces() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            Some(1),
+            Some(1),
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                no_indel: true,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let scan = ambig.scan(&mut bam);
+        let traces = ambig.strand_traces(&scan.passed());
+        let a = serde_json::to_value(&traces[0]).unwrap();
+        let g = serde_json::to_value(&traces[1]).unwrap();
+        assert_eq!(a["name"], "A");
+        assert_eq!(a["x"], serde_json::json!([1, 1]));
+        assert_eq!(a["y"], serde_json::json!([4, -3]));
+        assert_eq!(a["text"], serde_json::json!(["4", "3"]));
+        assert_eq!(
+            a["hovertext"],
+            serde_json::json!([
+                "A forward: 4<br>strand bias: pass",
+                "A reverse: 3<br>strand bias: pass"
+            ])
+        );
+        assert_eq!(g["name"], "G");
+        assert_eq!(g["y"], serde_json::json!([3, -2]));
+    }
+
+    #[test]
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn test_pages() {
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        let ambig = Ambig::new(
+            "",
+            Some("chr1"),
+            None,
+            None,
+            Settings {
+                threshold: 0.1,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                max_positions_per_plot: 2,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "ambig_test", true),
+        )
+        .unwrap();
+        let scan = ambig.scan(&mut bam);
+        let passed = scan.passed();
+        let pages = ambig.pages(&passed);
+        assert_eq!(
+            pages.iter().map(|page| page.len()).collect::<Vec<usize>>(),
+            vec![2, 1]
+        );
+        assert_eq!(page_kind("ambig", 2, 2), "ambig.page2");
+        assert_eq!(page_kind("ambig", 1, 1), "ambig");
+        assert!(!ambig.show_labels(MAX_LABELLED_POSITIONS + 1));
+    }
 }
@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Write;
+
+#[cfg(feature = "plot")]
+use plotly::{
+    common::{Marker, Mode, Title},
+    layout::{Axis, AxisType::Category},
+    Scatter,
+};
+use rust_htslib::bam::Read;
+
+use crate::commands::ambig::{Ambig, Pileup};
+use crate::commands::utils::{
+    get_tids, pileup_columns, plot_outputs, plot_supported, read_bam, sample_name, OutputPaths,
+};
+#[cfg(feature = "plot")]
+use crate::commands::utils::{write_plot, PLOT_EXT};
+use crate::config::RunSettings;
+use crate::provenance::Provenance;
+
+const BASES: [char; 6] = ['A', 'C', 'G', 'T', '-', '+'];
+
+/// A position where the minor allele frequency differs between samples
+#[derive(Debug, PartialEq)]
+struct AlleleChange {
+    pos: u32,
+    major: char,
+    minor: char,
+    // frequency of the minor allele in each sample, None if no reads passed qc
+    freqs: Vec<Option<f64>>,
+    change: f64,
+}
+
+pub struct Compare<'a> {
+    samples: Vec<Ambig<'a>>,
+    labels: Vec<String>,
+    change_threshold: f64,
+    output: OutputPaths,
+    provenance: Provenance,
+}
+
+impl<'a> Compare<'a> {
+    pub fn new(
+        samples: Vec<Ambig<'a>>,
+        labels: Option<Vec<String>>,
+        change_threshold: f64,
+        output: OutputPaths,
+    ) -> Self {
+        let labels = match labels {
+            Some(labels) => {
+                if labels.len() != samples.len() {
+                    panic!("Number of labels must match number of inputs");
+                }
+                labels
+            }
+            None => samples
+                .iter()
+                .map(|sample| sample_name(sample.input))
+                .collect(),
+        };
+        let provenance = Provenance::new(
+            samples
+                .iter()
+                .map(|sample| sample.input.to_string())
+                .collect(),
+            samples[0].chrom,
+            samples[0].start,
+            samples[0].stop,
+            Some(RunSettings::Scan(Box::new(samples[0].settings.clone()))),
+        );
+        let output = output.for_region(samples[0].chrom, samples[0].start, samples[0].stop);
+        Self {
+            samples,
+            labels,
+            change_threshold,
+            output,
+            provenance,
+        }
+    }
+
+    fn find_candidates(&self, tid: &str) -> BTreeSet<u32> {
+        // any position flagged as ambiguous in at least one sample is a candidate
+        let mut candidates = BTreeSet::new();
+        for sample in &self.samples {
+            let mut bam = read_bam(sample.input);
+            bam.fetch((tid, sample.start, sample.stop))
+                .expect("Failed to fetch region");
+            candidates.extend(sample.produce_pileup(&mut bam).into_keys());
+        }
+        candidates
+    }
+
+    fn collect_pileups(&self, tid: &str, candidates: &BTreeSet<u32>) -> Vec<BTreeMap<u32, Pileup>> {
+        let mut pileups = Vec::new();
+        for sample in &self.samples {
+            let mut sample_pileups = BTreeMap::new();
+            let mut bam = read_bam(sample.input);
+            bam.fetch((tid, sample.start, sample.stop))
+                .expect("Failed to fetch region");
+            for pileup in pileup_columns(&mut bam, sample.settings.max_depth).flatten() {
+                if !candidates.contains(&(pileup.pos() + 1)) {
+                    continue;
+                }
+                let counts = sample.count_column(&pileup, tid);
+                if sample.compared_depth(pileup.depth(), &counts) >= sample.settings.depth_threshold
+                {
+                    sample_pileups.insert(pileup.pos() + 1, counts.pileup);
+                }
+            }
+            pileups.push(sample_pileups);
+        }
+        pileups
+    }
+
+    fn minor_allele_changes(
+        &self,
+        candidates: &BTreeSet<u32>,
+        pileups: &[BTreeMap<u32, Pileup>],
+    ) -> Vec<AlleleChange> {
+        let mut changes = Vec::new();
+        for pos in candidates {
+            let sample_pileups: Vec<Option<&Pileup>> = pileups
+                .iter()
+                .map(|sample| sample.get(pos).filter(|pileup| pileup.get_total() > 0))
+                .collect();
+
+            // the major allele of the earliest sample with coverage is the baseline
+            let major = match sample_pileups.iter().flatten().next() {
+                Some(pileup) => pileup.get_major_variant(),
+                None => continue,
+            };
+
+            // the minor allele is the non-baseline allele most frequent across all samples
+            let minor = BASES
+                .iter()
+                .filter(|base| **base != major)
+                .map(|base| {
+                    let summed: f64 = sample_pileups
+                        .iter()
+                        .flatten()
+                        .map(|pileup| pileup.get_proportion(*base))
+                        .sum();
+                    (*base, summed)
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(base, _)| base)
+                .expect("No bases found in base_counts.");
+
+            let freqs: Vec<Option<f64>> = sample_pileups
+                .iter()
+                .map(|pileup| {
+                    pileup.map(|pileup| {
+                        let freq = pileup.get_proportion(minor);
+                        (freq * 10000.0).round() / 10000.0
+                    })
+                })
+                .collect();
+
+            let observed: Vec<f64> = freqs.iter().flatten().cloned().collect();
+            if observed.len() < 2 {
+                continue;
+            }
+            let max = observed.iter().cloned().fold(f64::MIN, f64::max);
+            let min = observed.iter().cloned().fold(f64::MAX, f64::min);
+            let change = ((max - min) * 10000.0).round() / 10000.0;
+
+            changes.push(AlleleChange {
+                pos: *pos,
+                major,
+                minor,
+                freqs,
+                change,
+            });
+        }
+        changes
+    }
+
+    fn output_tsv(&self, tid: &str, changes: &[AlleleChange]) -> String {
+        let out_name = self.output.contig_path(
+            tid,
+            self.samples[0].start,
+            self.samples[0].stop,
+            "compare",
+            "tsv",
+        );
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        for line in self.provenance.header_lines() {
+            writeln!(file, "# {}", line).expect("Failed to write to file");
+        }
+        writeln!(
+            file,
+            "pos\tmajor\tminor\t{}\tchange",
+            self.labels.join("\t")
+        )
+        .expect("Failed to write to file");
+        for change in changes
+            .iter()
+            .filter(|change| change.change > self.change_threshold)
+        {
+            let freqs: Vec<String> = change
+                .freqs
+                .iter()
+                .map(|freq| match freq {
+                    Some(freq) => freq.to_string(),
+                    None => "NA".to_string(),
+                })
+                .collect();
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{}",
+                change.pos,
+                change.major,
+                change.minor,
+                freqs.join("\t"),
+                change.change
+            )
+            .expect("Failed to write to file");
+        }
+        out_name
+    }
+
+    #[cfg(feature = "plot")]
+    fn plot_scatter(&self, tid: &str, changes: &[AlleleChange]) -> String {
+        let mut plot = plotly::Plot::new();
+        // plot the baseline sample against every later sample
+        for (i, label) in self.labels.iter().enumerate().skip(1) {
+            let points: Vec<(f64, f64, String)> = changes
+                .iter()
+                .filter_map(|change| match (change.freqs[0], change.freqs[i]) {
+                    (Some(x), Some(y)) => Some((x, y, change.pos.to_string())),
+                    _ => None,
+                })
+                .collect();
+            let trace = Scatter::new(
+                points.iter().map(|p| p.0).collect(),
+                points.iter().map(|p| p.1).collect(),
+            )
+            .name(format!("{} vs {}", self.labels[0], label).as_str())
+            .mode(Mode::Markers)
+            .text_array(points.iter().map(|p| p.2.clone()).collect())
+            .marker(Marker::new().size(10));
+            plot.add_trace(trace);
+        }
+        let diagonal = Scatter::new(vec![0.0, 1.0], vec![0.0, 1.0])
+            .name("No change")
+            .mode(Mode::Lines);
+        plot.add_trace(diagonal);
+
+        let layout = plotly::Layout::new()
+            .title(Title::new(&format!(
+                "Minor Allele Frequency<br><sup>{}</sup>",
+                self.provenance.subtitle()
+            )))
+            .annotations(vec![self.provenance.annotation()])
+            .x_axis(
+                Axis::new()
+                    .title(Title::new(&self.labels[0]))
+                    .range(vec![0.0, 1.0]),
+            )
+            .y_axis(
+                Axis::new()
+                    .title(Title::new("Later timepoint"))
+                    .range(vec![0.0, 1.0]),
+            );
+        plot.set_layout(layout);
+
+        let out_name = self.output.contig_path(
+            tid,
+            self.samples[0].start,
+            self.samples[0].stop,
+            "scatter",
+            PLOT_EXT,
+        );
+        write_plot(&plot, &out_name, 1000, 1000);
+        out_name
+    }
+
+    #[cfg(feature = "plot")]
+    fn plot_trajectory(&self, tid: &str, changes: &[AlleleChange]) -> String {
+        let mut plot = plotly::Plot::new();
+        for change in changes
+            .iter()
+            .filter(|change| change.change > self.change_threshold)
+        {
+            let points: Vec<(String, f64)> = self
+                .labels
+                .iter()
+                .zip(&change.freqs)
+                .filter_map(|(label, freq)| freq.map(|freq| (label.clone(), freq)))
+                .collect();
+            let trace = Scatter::new(
+                points.iter().map(|p| p.0.clone()).collect(),
+                points.iter().map(|p| p.1).collect(),
+            )
+            .name(format!("{} {}>{}", change.pos, change.major, change.minor).as_str())
+            .mode(Mode::LinesMarkers);
+            plot.add_trace(trace);
+        }
+
+        let layout = plotly::Layout::new()
+            .title(Title::new(&format!(
+                "Minor Allele Trajectory<br><sup>{}</sup>",
+                self.provenance.subtitle()
+            )))
+            .annotations(vec![self.provenance.annotation()])
+            .x_axis(Axis::new().title(Title::new("Sample")).type_(Category))
+            .y_axis(
+                Axis::new()
+                    .title(Title::new("Minor allele frequency"))
+                    .range(vec![0.0, 1.0]),
+            );
+        plot.set_layout(layout);
+
+        let out_name = self.output.contig_path(
+            tid,
+            self.samples[0].start,
+            self.samples[0].stop,
+            "trajectory",
+            PLOT_EXT,
+        );
+        write_plot(&plot, &out_name, 2000, 1000);
+        out_name
+    }
+
+    pub fn run(&self) -> Result<(), String> {
+        let bam = read_bam(self.samples[0].input);
+        let tids = get_tids(self.samples[0].chrom, Some(bam.header()));
+        println!("Tids: {:?}", tids);
+        let mut contig_outputs = plot_outputs(&["scatter", "trajectory"]);
+        contig_outputs.push(("compare", "tsv"));
+        self.output
+            .check_free(&tids, &contig_outputs, &[("compare.manifest", "json")])?;
+        let mut outputs = Vec::new();
+        #[cfg_attr(not(feature = "plot"), allow(unused_variables))]
+        let scatter = plot_supported("scatter", false);
+        #[cfg_attr(not(feature = "plot"), allow(unused_variables))]
+        let trajectory = plot_supported("trajectory", false);
+        for tid in &tids {
+            println!("Processing Tid: {}", tid);
+            let candidates = self.find_candidates(tid);
+            let pileups = self.collect_pileups(tid, &candidates);
+            let changes = self.minor_allele_changes(&candidates, &pileups);
+            outputs.push(self.output_tsv(tid, &changes));
+            #[cfg(feature = "plot")]
+            if scatter {
+                outputs.push(self.plot_scatter(tid, &changes));
+            }
+            #[cfg(feature = "plot")]
+            if trajectory {
+                outputs.push(self.plot_trajectory(tid, &changes));
+            }
+        }
+        self.provenance
+            .write_manifest(&self.output.run_path("compare.manifest", "json"), &outputs);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    fn sample(input: &str) -> Ambig<'_> {
+        Ambig::new(
+            input,
+            Some("chr1"),
+            Some(1),
+            Some(10),
+            Settings {
+                threshold: 0.2,
+                base_quality_threshold: 1,
+                map_quality_threshold: 1,
+                depth_threshold: 1,
+                strand_bias_threshold: 0.0,
+                ..Settings::default()
+            },
+            OutputPaths::new("testing", "compare_test", true),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_minor_allele_changes() {
+        let compare = Compare::new(
+            vec![sample("a.bam"), sample("b.bam")],
+            None,
+            0.2,
+            OutputPaths::new("testing", "compare_test", true),
+        );
+        assert_eq!(compare.labels, vec!["a", "b"]);
+
+        let mut first = Pileup::new();
+        first.a = (90, (45, 45));
+        first.g = (10, (5, 5));
+        let mut second = Pileup::new();
+        second.a = (40, (20, 20));
+        second.g = (60, (30, 30));
+
+        let candidates = BTreeSet::from([5, 6]);
+        let pileups = vec![BTreeMap::from([(5, first)]), BTreeMap::from([(5, second)])];
+        let changes = compare.minor_allele_changes(&candidates, &pileups);
+
+        // position 6 has no coverage in either sample so is not reported
+        assert_eq!(
+            changes,
+            vec![AlleleChange {
+                pos: 5,
+                major: 'A',
+                minor: 'G',
+                freqs: vec![Some(0.1), Some(0.6)],
+                change: 0.5,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_compare_identical_samples() {
+        let compare = Compare::new(
+            vec![sample("test-data/ambig.bam"), sample("test-data/ambig.bam")],
+            None,
+            0.2,
+            OutputPaths::new("testing", "compare_test", true),
+        );
+        let candidates = compare.find_candidates("chr1");
+        let pileups = compare.collect_pileups("chr1", &candidates);
+        let changes = compare.minor_allele_changes(&candidates, &pileups);
+        assert!(!changes.is_empty());
+        assert!(changes.iter().all(|change| change.change == 0.0));
+    }
+}
@@ -1,13 +1,36 @@
 use rust_htslib::bam::{IndexedReader, Read};
 
-use crate::commands::utils::{get_start_stop, get_tids, read_bam};
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+use crate::commands::utils::sample_name;
+use crate::commands::utils::{
+    fragment_depth, get_start_stop, get_tids, pileup_columns, plot_outputs, plot_supported,
+    read_bam, OutputPaths,
+};
+#[cfg(all(feature = "plot", not(feature = "native-plot")))]
+use crate::commands::utils::{write_plot, PLOT_EXT};
+use crate::config::{DepthSettings, RunSettings};
+use crate::json::DepthJson;
+#[cfg(any(feature = "plot", feature = "native-plot"))]
+use crate::provenance::format_region;
+use crate::provenance::Provenance;
+#[cfg(feature = "native-plot")]
+use crate::render::{self, Titles};
+use crate::style::PlotStyle;
 
 pub struct Depth<'a> {
     input: &'a str,
     chrom: Option<&'a str>,
     start: u32,
     stop: u32,
-    output: String,
+    output: OutputPaths,
+    json: bool,
+    // reads held in a pileup column, 0 for no limit
+    max_depth: u32,
+    // count overlapping mates of a pair once
+    dedup_overlaps: bool,
+    #[cfg_attr(not(any(feature = "plot", feature = "native-plot")), allow(dead_code))]
+    style: PlotStyle,
+    provenance: Provenance,
 }
 
 impl<'a> Depth<'a> {
@@ -16,54 +39,156 @@ impl<'a> Depth<'a> {
         chrom: Option<&'a str>,
         start: Option<u32>,
         stop: Option<u32>,
-        output: String,
+        output: OutputPaths,
+        json: bool,
+        style: PlotStyle,
     ) -> Self {
         let (start, stop) = get_start_stop(start, stop);
+        let output = output.for_region(chrom, start, stop);
+        let settings = DepthSettings::default();
+        let provenance = Provenance::new(
+            vec![input.to_string()],
+            chrom,
+            start,
+            stop,
+            Some(RunSettings::Depth(settings.clone())),
+        );
         Self {
             input,
             chrom,
             start,
             stop,
             output,
+            json,
+            max_depth: settings.max_depth,
+            dedup_overlaps: settings.dedup_overlaps,
+            style,
+            provenance,
         }
     }
 
-    fn plot(&self, x: Vec<u32>, y: Vec<u32>) {
+    /// Set the htslib pileup depth limit and whether overlapping mates are counted once
+    pub fn pileup_options(mut self, max_depth: u32, dedup_overlaps: bool) -> Self {
+        self.max_depth = max_depth;
+        self.dedup_overlaps = dedup_overlaps;
+        self.provenance.settings = Some(RunSettings::Depth(DepthSettings {
+            max_depth,
+            dedup_overlaps,
+        }));
+        self
+    }
+
+    #[cfg(any(feature = "plot", feature = "native-plot"))]
+    fn plot(&self, x: Vec<u32>, y: Vec<u32>, tid: &str) -> String {
+        let title = self.style.title(
+            "Depth",
+            &sample_name(self.input),
+            tid,
+            &format_region(Some(tid), self.start, self.stop),
+        );
+        #[cfg(feature = "native-plot")]
+        let ext = "png";
+        #[cfg(not(feature = "native-plot"))]
+        let ext = PLOT_EXT;
+        let out_name = self
+            .output
+            .contig_path(tid, self.start, self.stop, "depth", ext);
+        self.render(x, y, &title, &out_name);
+        out_name
+    }
+
+    #[cfg(all(feature = "plot", not(feature = "native-plot")))]
+    fn render(&self, x: Vec<u32>, y: Vec<u32>, title: &str, out_name: &str) {
         let trace = plotly::Scatter::new(x, y)
             .name("Depth")
-            .mode(plotly::common::Mode::Lines);
+            .mode(plotly::common::Mode::Lines)
+            .line(plotly::common::Line::new().color(self.style.colour("depth")));
 
+        let layout = self.style.apply(
+            plotly::Layout::new()
+                .title(plotly::common::Title::new(&format!(
+                    "{}<br><sup>{}</sup>",
+                    title,
+                    self.provenance.subtitle()
+                )))
+                .annotations(vec![self.provenance.annotation()]),
+        );
         let mut plot = plotly::Plot::new();
         plot.add_trace(trace);
-        let out_name = format!("{}.png", self.output);
-        plot.write_image(out_name, plotly::ImageFormat::PNG, 2000, 1000, 1.0);
+        plot.set_layout(layout);
+        write_plot(&plot, out_name, 2000, 1000);
+    }
+
+    #[cfg(feature = "native-plot")]
+    fn render(&self, x: Vec<u32>, y: Vec<u32>, title: &str, out_name: &str) {
+        render::line_chart(
+            out_name,
+            &Titles {
+                title,
+                subtitle: &self.provenance.subtitle(),
+                footer: &self.provenance.command_line,
+            },
+            &x,
+            &y,
+            &self.style,
+        );
     }
 
-    fn process_pileup(&self, bam: &mut IndexedReader) -> (Vec<u32>, Vec<u32>) {
+    pub(crate) fn process_pileup(&self, bam: &mut IndexedReader) -> (Vec<u32>, Vec<u32>) {
         let mut x = Vec::new();
         let mut y = Vec::new();
 
-        for p in bam.pileup().flatten() {
+        for p in pileup_columns(bam, self.max_depth).flatten() {
             let pos = p.pos();
             if pos >= self.start && pos < self.stop {
                 x.push(pos + 1);
-                y.push(p.depth());
+                y.push(if self.dedup_overlaps {
+                    fragment_depth(&p)
+                } else {
+                    p.depth()
+                });
             }
         }
         (x, y)
     }
 
-    pub fn run(&self) {
+    pub fn run(&self) -> Result<(), String> {
         let mut bam = read_bam(self.input);
         let tids = get_tids(self.chrom, Some(bam.header()));
         println!("Tids: {:?}", tids);
+        self.output.check_free(
+            &tids,
+            &plot_outputs(&["depth"]),
+            &[("depth", "json"), ("depth.manifest", "json")],
+        )?;
 
+        let mut outputs = Vec::new();
+        let mut json = DepthJson::new(&self.provenance);
+        #[cfg_attr(
+            not(any(feature = "plot", feature = "native-plot")),
+            allow(unused_variables)
+        )]
+        let plot = plot_supported("depth", true);
         for tid in &tids {
             bam.fetch((tid, self.start, self.stop))
                 .expect("Failed to fetch region");
             let (x, y) = self.process_pileup(&mut bam);
-            self.plot(x, y);
+            if self.json {
+                json.add_contig(tid, &x, &y);
+            }
+            #[cfg(any(feature = "plot", feature = "native-plot"))]
+            if plot {
+                outputs.push(self.plot(x, y, tid));
+            }
+        }
+        if self.json {
+            let out_name = self.output.run_path("depth", "json");
+            json.write(&out_name);
+            outputs.push(out_name);
         }
+        self.provenance
+            .write_manifest(&self.output.run_path("depth.manifest", "json"), &outputs);
+        Ok(())
     }
 }
 
@@ -81,9 +206,9 @@ mod tests {
         // first we build a header
         let mut header = bam::Header::new();
         header.push_record(
-            &bam::header::HeaderRecord::new(b"SQ")
-                .push_tag(b"SN", &"chr1")
-                .push_tag(b"LN", &1000),
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 1000),
         );
         let header_view = bam::HeaderView::from_header(&header);
         let mut writer = bam::Writer::from_path(temp_path, &header, bam::Format::Bam).unwrap();
@@ -139,10 +264,64 @@ mod tests {
             Some("chr1"),
             Some(1),
             Some(15),
-            "testing/depth_test".to_string(),
+            OutputPaths::new("testing", "depth_test", true),
+            false,
+            PlotStyle::default(),
         );
         let (x, y) = depth.process_pileup(&mut bam);
         assert_eq!(x, vec![5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
         assert_eq!(y, vec![6, 6, 6, 6, 6, 6, 6, 5, 5, 5]);
     }
+
+    #[rstest]
+    fn test_process_pileup_dedup_overlaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("depth_overlap_test.bam");
+        let temp_path = path.to_str().unwrap();
+        let mut header = bam::Header::new();
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 1000),
+        );
+        let header_view = bam::HeaderView::from_header(&header);
+        let mut writer = bam::Writer::from_path(temp_path, &header, bam::Format::Bam).unwrap();
+        let records: [&[u8]; 3] = [
+            // mates overlapping at positions 8 to 10
+            b"pair1\t99\tchr1\t5\t60\t6M\t=\t8\t9\tGGGGGG\tFFFFFF",
+            b"read2\t0\tchr1\t5\t60\t9M\t*\t0\t0\tGGGGGGGGG\tFFFFFFFFF",
+            b"pair1\t147\tchr1\t8\t60\t6M\t=\t5\t-9\tGGGGGG\tFFFFFF",
+        ];
+        for record in records {
+            writer
+                .write(&Record::from_sam(&header_view, record).unwrap())
+                .unwrap();
+        }
+        drop(writer);
+        bam::index::build(temp_path, None, bam::index::Type::Bai, 1).unwrap();
+        let mut bam = bam::IndexedReader::from_path(temp_path).unwrap();
+
+        let depth = |dedup_overlaps| {
+            Depth::new(
+                temp_path,
+                Some("chr1"),
+                Some(1),
+                Some(15),
+                OutputPaths::new("testing", "depth_test", true),
+                false,
+                PlotStyle::default(),
+            )
+            .pileup_options(0, dedup_overlaps)
+        };
+        let (x, y) = depth(false).process_pileup(&mut bam);
+        assert_eq!(x, vec![5, 6, 7, 8, 9, 10, 11, 12, 13]);
+        assert_eq!(y, vec![2, 2, 2, 3, 3, 3, 2, 2, 2]);
+        bam.fetch("chr1").unwrap();
+        let (_, y) = depth(true).process_pileup(&mut bam);
+        assert_eq!(y, vec![2; 9]);
+        assert!(depth(true)
+            .provenance
+            .header_lines()
+            .contains(&"settings={\"max_depth\":0,\"dedup_overlaps\":true}".to_string()));
+    }
 }
@@ -1,3 +1,6 @@
 pub mod ambig;
+pub mod compare;
 pub mod depth;
+#[cfg(feature = "html")]
+pub mod report;
 pub mod utils;
@@ -0,0 +1,370 @@
+use std::fs::File;
+use std::io::Write;
+
+use plotly::{
+    common::{Line, Mode, Title},
+    layout::{Axis, BarMode},
+    Plot, Scatter,
+};
+use rust_htslib::bam::Read;
+
+use crate::commands::ambig::{Ambig, ContigScan, ContigSummary};
+use crate::commands::depth::Depth;
+use crate::commands::utils::{get_tids, read_bam, OutputPaths};
+use crate::config::RunSettings;
+use crate::provenance::Provenance;
+
+const ALLELES: [char; 6] = ['A', 'C', 'G', 'T', '-', '+'];
+
+// Click a column header to sort the table by that column, click again to reverse
+const SORT_SCRIPT: &str = r#"<script type="text/javascript">
+document.querySelectorAll("table.sortable th").forEach(function (th, index) {
+    th.addEventListener("click", function () {
+        const body = th.closest("table").tBodies[0];
+        const ascending = th.dataset.order !== "asc";
+        th.dataset.order = ascending ? "asc" : "desc";
+        Array.from(body.rows)
+            .sort(function (a, b) {
+                const x = a.cells[index].textContent;
+                const y = b.cells[index].textContent;
+                const order = isNaN(x) || isNaN(y) ? x.localeCompare(y) : x - y;
+                return ascending ? order : -order;
+            })
+            .forEach(function (row) { body.appendChild(row); });
+    });
+});
+</script>"#;
+
+const STYLE: &str = r#"<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.75em; text-align: right; }
+th { background: #eee; }
+table.sortable th { cursor: pointer; }
+.plot { height: 700px; }
+</style>"#;
+
+/// Everything needed to render one contig of the report
+struct ContigReport {
+    name: String,
+    depth: (Vec<u32>, Vec<u32>),
+    scan: ContigScan,
+    summary: ContigSummary,
+}
+
+pub struct Report<'a> {
+    ambig: Ambig<'a>,
+    depth: Depth<'a>,
+    output: OutputPaths,
+    provenance: Provenance,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(ambig: Ambig<'a>, depth: Depth<'a>, output: OutputPaths) -> Self {
+        let provenance = Provenance::new(
+            vec![ambig.input.to_string()],
+            ambig.chrom,
+            ambig.start,
+            ambig.stop,
+            Some(RunSettings::Scan(Box::new(ambig.settings.clone()))),
+        );
+        let output = output.for_region(ambig.chrom, ambig.start, ambig.stop);
+        Self {
+            ambig,
+            depth,
+            output,
+            provenance,
+        }
+    }
+
+    /// Depth track above the ambiguity bars, sharing one genome coordinate axis
+    fn plot(&self, contig: &ContigReport) -> Plot {
+        let (x, y) = contig.depth.clone();
+        let depth = Scatter::new(x, y)
+            .name("Depth")
+            .mode(Mode::Lines)
+            .line(Line::new().color(self.ambig.settings.style.colour("depth")));
+        let mut plot = Plot::new();
+        plot.add_trace(depth);
+        for bar in self.ambig.bar_traces(&contig.scan.passed()) {
+            plot.add_trace(bar.y_axis("y2"));
+        }
+        plot.set_layout(
+            self.ambig.settings.style.apply(
+                plotly::Layout::new()
+                    .bar_mode(BarMode::Stack)
+                    .title(Title::new(&contig.name))
+                    .x_axis(Axis::new().title(Title::new("Position")))
+                    .y_axis(Axis::new().title(Title::new("Depth")).domain(&[0.55, 1.0]))
+                    .y_axis2(
+                        Axis::new()
+                            .title(Title::new("Proportion"))
+                            .domain(&[0.0, 0.45])
+                            .anchor("x"),
+                    ),
+            ),
+        );
+        plot
+    }
+
+    fn summary_table(&self, contigs: &[ContigReport]) -> String {
+        let mut html = String::from(
+            "<table>\n<tr><th>Contig</th><th>Positions scanned</th><th>Positions covered</th>\
+             <th>Positions ambiguous</th><th>Positions masked</th><th>Positions passed</th><th>Multi-allelic</th><th>Fixed differences</th><th>Passed per kb</th>\
+             <th>Median minor proportion</th><th>Overlapping pairs</th><th>Mate conflicts</th><th>Removed: empty seq</th>\
+             <th>Removed: min-BQ</th><th>Removed: min-MQ</th><th>Removed: indel</th><th>Removed: read end</th><th>Removed: homopolymer</th><th>Removed: overlap</th></tr>\n",
+        );
+        for contig in contigs {
+            let summary = &contig.summary;
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&contig.name),
+                summary.positions_scanned,
+                summary.positions_covered,
+                summary.positions_ambiguous,
+                summary.positions_masked,
+                summary.positions_passed,
+                summary.positions_multiallelic,
+                summary
+                    .fixed_differences
+                    .map_or("NA".to_string(), |count| count.to_string()),
+                summary.passed_per_kb,
+                summary
+                    .median_minor_proportion
+                    .map_or("NA".to_string(), |median| median.to_string()),
+                summary.overlapping_pairs,
+                summary.mate_conflicts,
+                summary.reads_removed.empty_seq,
+                summary.reads_removed.base_quality,
+                summary.reads_removed.map_quality,
+                summary.reads_removed.indel,
+                summary.reads_removed.read_end,
+                summary.reads_removed.homopolymer,
+                summary.reads_removed.overlap
+            ));
+        }
+        html.push_str("</table>\n");
+        html
+    }
+
+    /// Every flagged position with its allele counts, sortable by any column
+    fn positions_table(&self, contigs: &[ContigReport]) -> String {
+        let mut html = String::from(
+            "<table class=\"sortable\">\n<thead><tr><th>Contig</th><th>Position</th>\
+             <th>Depth</th><th>Filtered depth</th><th>Total</th><th>Major</th><th>Minor proportion</th><th>Second allele</th>\
+             <th>Allele count</th><th>Status</th>",
+        );
+        for allele in ALLELES {
+            html.push_str(&format!("<th>{}</th>", allele));
+        }
+        html.push_str("</tr></thead>\n<tbody>\n");
+        for contig in contigs {
+            for result in &contig.scan.positions {
+                let status = if result.passed {
+                    "PASS".to_string()
+                } else {
+                    result
+                        .reasons
+                        .iter()
+                        .map(|reason| reason.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(",")
+                };
+                let second = result
+                    .second_allele()
+                    .map_or(String::new(), |(allele, proportion)| {
+                        format!("{} {:.4}", allele, proportion)
+                    });
+                html.push_str(&format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                     <td>{:.4}</td><td>{}</td><td>{}</td><td>{}</td>",
+                    escape_html(&contig.name),
+                    result.pos,
+                    result.depth,
+                    result.filtered_depth,
+                    result.pileup.get_total(),
+                    result.major,
+                    result.minor_proportion,
+                    second,
+                    result.allele_count,
+                    status
+                ));
+                for allele in ALLELES {
+                    html.push_str(&format!(
+                        "<td>{}</td>",
+                        result.pileup.get_base_count(allele)
+                    ));
+                }
+                html.push_str("</tr>\n");
+            }
+        }
+        html.push_str("</tbody>\n</table>\n");
+        html
+    }
+
+    fn render(&self, contigs: &[ContigReport]) -> String {
+        let mut html = String::from("<!doctype html>\n<html lang=\"en\">\n<head>\n");
+        html.push_str("<meta charset=\"utf-8\" />\n<title>ambigviz report</title>\n");
+        html.push_str(STYLE);
+        html.push_str("\n</head>\n<body>\n<h1>ambigviz report</h1>\n");
+        html.push_str(&plotly_js());
+
+        html.push_str("<h2>Parameters</h2>\n<table>\n");
+        for line in self.provenance.header_lines() {
+            let (key, value) = line.split_once('=').unwrap_or((&line, ""));
+            html.push_str(&format!(
+                "<tr><th>{}</th><td style=\"text-align: left\">{}</td></tr>\n",
+                escape_html(key),
+                escape_html(value)
+            ));
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Summary</h2>\n");
+        html.push_str(&self.summary_table(contigs));
+
+        for (index, contig) in contigs.iter().enumerate() {
+            html.push_str(&format!(
+                "<h2>{}</h2>\n<div class=\"plot\">\n",
+                escape_html(&contig.name)
+            ));
+            html.push_str(
+                &self
+                    .plot(contig)
+                    .to_inline_html(Some(&format!("contig-{}", index))),
+            );
+            html.push_str("\n</div>\n");
+        }
+
+        html.push_str("<h2>Positions</h2>\n");
+        html.push_str(&self.positions_table(contigs));
+        html.push_str(SORT_SCRIPT);
+        html.push_str("\n</body>\n</html>\n");
+        html
+    }
+
+    pub fn run(&self) -> Result<(), String> {
+        let mut bam = read_bam(self.ambig.input);
+        let tids = get_tids(self.ambig.chrom, Some(bam.header()));
+        println!("Tids: {:?}", tids);
+        self.output.check_free(
+            &tids,
+            &[],
+            &[("report", "html"), ("report.manifest", "json")],
+        )?;
+        let mut contigs = Vec::new();
+        for tid in &tids {
+            println!("Processing Tid: {}", tid);
+            bam.fetch((tid, self.ambig.start, self.ambig.stop))
+                .expect("Failed to fetch region");
+            let depth = self.depth.process_pileup(&mut bam);
+            bam.fetch((tid, self.ambig.start, self.ambig.stop))
+                .expect("Failed to fetch region");
+            let scan = self.ambig.scan(&mut bam);
+            let summary = scan.summary();
+            contigs.push(ContigReport {
+                name: tid.to_string(),
+                depth,
+                scan,
+                summary,
+            });
+        }
+
+        let out_name = self.output.run_path("report", "html");
+        let mut file = File::create(&out_name).expect("Failed to create file");
+        file.write_all(self.render(&contigs).as_bytes())
+            .expect("Failed to write to file");
+        self.provenance.write_manifest(
+            &self.output.run_path("report.manifest", "json"),
+            &[out_name],
+        );
+        Ok(())
+    }
+}
+
+/// The plotly.js library as an inline script element, so the report works offline
+fn plotly_js() -> String {
+    let mut plot = Plot::new();
+    plot.use_local_plotly();
+    let html = plot.to_html();
+    let open = "<script type=\"text/javascript\">";
+    let start = html
+        .find(open)
+        .expect("plotly.js not found in plot template");
+    let end = html[start..]
+        .find("</script>")
+        .expect("plotly.js not found in plot template")
+        + start
+        + "</script>".len();
+    html[start..end].to_string()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_htslib::bam;
+
+    use super::*;
+    use crate::config::Settings;
+    use crate::style::PlotStyle;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_render_report() {
+        let output = OutputPaths::new("testing", "report_test", true);
+        let settings = Settings {
+            threshold: 0.1,
+            base_quality_threshold: 1,
+            map_quality_threshold: 1,
+            depth_threshold: 1,
+            ..Settings::default()
+        };
+        let ambig = Ambig::new(
+            "test-data/ambig.bam",
+            Some("chr1"),
+            None,
+            None,
+            settings,
+            output.clone(),
+        )
+        .unwrap();
+        let depth = Depth::new(
+            "test-data/ambig.bam",
+            Some("chr1"),
+            None,
+            None,
+            output.clone(),
+            false,
+            PlotStyle::default(),
+        );
+        let report = Report::new(ambig, depth, output);
+        let mut bam = bam::IndexedReader::from_path("test-data/ambig.bam").unwrap();
+        bam.fetch("chr1").unwrap();
+        let scan = report.ambig.scan(&mut bam);
+        let contig = ContigReport {
+            name: "chr1".to_string(),
+            depth: (vec![1, 2, 3], vec![12, 12, 12]),
+            summary: scan.summary(),
+            scan,
+        };
+        let html = report.render(&[contig]);
+        assert!(html.contains("Plotly.newPlot(\"contig-0\""));
+        assert!(!html.contains("<script src="));
+        assert!(html.contains("<td>chr1</td><td>9</td>"));
+        assert_eq!(html.matches("<td>PASS</td>").count(), 3);
+    }
+}
@@ -1,8 +1,15 @@
+use std::collections::HashSet;
 use std::error::Error;
-use std::path;
+use std::fs;
+use std::io::Read as _;
+use std::path::{self, PathBuf};
 
 use rust_htslib::bam;
-use rust_htslib::bam::IndexedReader;
+use rust_htslib::bam::{IndexedReader, Read};
+use rust_htslib::bgzf;
+
+// the first two bytes of every gzip (and so bgzip) file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 pub fn get_tids(chrom: Option<&str>, header: Option<&bam::HeaderView>) -> Vec<String> {
     match chrom {
@@ -26,6 +33,229 @@ pub fn get_start_stop(start: Option<u32>, stop: Option<u32>) -> (u32, u32) {
     }
 }
 
+/// Pileup columns over the fetched region, holding at most `max_depth` reads in a column, 0 for no
+/// limit
+pub(crate) fn pileup_columns(
+    bam: &mut IndexedReader,
+    max_depth: u32,
+) -> bam::pileup::Pileups<'_, IndexedReader> {
+    let mut pileups = bam.pileup();
+    pileups.set_max_depth(if max_depth == 0 {
+        i32::MAX as u32
+    } else {
+        max_depth
+    });
+    pileups
+}
+
+/// Number of fragments in a pileup column, counting overlapping mates of a pair once
+pub(crate) fn fragment_depth(pileup: &bam::pileup::Pileup) -> u32 {
+    let mut names = HashSet::new();
+    for alignment in pileup.alignments() {
+        names.insert(alignment.record().qname().to_vec());
+    }
+    names.len() as u32
+}
+
+/// The file name of an input without its directory or extension
+pub fn sample_name(path: &str) -> String {
+    path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Extension of plots drawn with plotly, HTML when ambigviz is built without kaleido
+#[cfg(feature = "plot")]
+pub(crate) const PLOT_EXT: &str = if cfg!(feature = "plot-kaleido") {
+    "png"
+} else {
+    "html"
+};
+
+/// Write a plotly plot as a PNG with kaleido, or as interactive HTML when built without it
+#[cfg(feature = "plot")]
+pub(crate) fn write_plot(plot: &plotly::Plot, path: &str, width: usize, height: usize) {
+    #[cfg(feature = "plot-kaleido")]
+    plot.write_image(path, plotly::ImageFormat::PNG, width, height, 1.0);
+    #[cfg(not(feature = "plot-kaleido"))]
+    {
+        let _ = (width, height);
+        plot.write_html(path);
+    }
+}
+
+/// Whether this build can draw a plot, telling the user when it will be skipped or written as
+/// HTML instead of PNG. `native` plots can also be drawn by the native-plot renderer
+pub fn plot_supported(plot: &str, native: bool) -> bool {
+    if cfg!(feature = "plot-kaleido") || (native && cfg!(feature = "native-plot")) {
+        true
+    } else if cfg!(feature = "plot") {
+        eprintln!(
+            "Writing the {} plot as HTML, PNG plots need ambigviz built with the plot-kaleido feature",
+            plot
+        );
+        true
+    } else {
+        eprintln!(
+            "Skipping the {} plot, ambigviz was built without the plot feature",
+            plot
+        );
+        false
+    }
+}
+
+/// Kind and extension of plot outputs for `OutputPaths::check_free`, with both extensions a
+/// plot can be written as
+pub(crate) fn plot_outputs<'a>(kinds: &[&'a str]) -> Vec<(&'a str, &'a str)> {
+    kinds
+        .iter()
+        .flat_map(|kind| [(*kind, "png"), (*kind, "html")])
+        .collect()
+}
+
+/// Names output files as `<prefix>.<contig>.<region>.<kind>.<ext>` inside the output directory,
+/// outputs covering the whole run are named after the run region as `<prefix>.<region>.<kind>.<ext>`
+#[derive(Debug, Clone)]
+pub struct OutputPaths {
+    outdir: PathBuf,
+    prefix: String,
+    force: bool,
+    // 0-based start and exclusive stop of the run, u32::MAX for the end of each contig
+    start: u32,
+    stop: u32,
+    // contig, region or `all` for the run level outputs
+    run_region: String,
+}
+
+impl OutputPaths {
+    pub fn new(outdir: &str, prefix: &str, force: bool) -> Self {
+        fs::create_dir_all(outdir).expect("Failed to create output directory");
+        Self {
+            outdir: PathBuf::from(outdir),
+            prefix: prefix.to_string(),
+            force,
+            start: 0,
+            stop: u32::MAX,
+            run_region: "all".to_string(),
+        }
+    }
+
+    /// Set the region of the run, `start` is 0-based and `stop` exclusive
+    pub fn for_region(mut self, chrom: Option<&str>, start: u32, stop: u32) -> Self {
+        self.start = start;
+        self.stop = stop;
+        self.run_region = match chrom {
+            Some(chrom) => format!("{}.{}", chrom, region_name(start, stop)),
+            None => "all".to_string(),
+        };
+        self
+    }
+
+    /// Path for an output covering one contig, `start` is 0-based and `stop` exclusive
+    pub fn contig_path(
+        &self,
+        contig: &str,
+        start: u32,
+        stop: u32,
+        kind: &str,
+        ext: &str,
+    ) -> String {
+        self.path(format!(
+            "{}.{}.{}.{}.{}",
+            self.prefix,
+            contig,
+            region_name(start, stop),
+            kind,
+            ext
+        ))
+    }
+
+    /// Path for an output covering the whole run
+    pub fn run_path(&self, kind: &str, ext: &str) -> String {
+        self.path(format!(
+            "{}.{}.{}.{}",
+            self.prefix, self.run_region, kind, ext
+        ))
+    }
+
+    fn path(&self, name: String) -> String {
+        self.outdir.join(name).to_string_lossy().to_string()
+    }
+
+    /// Check that no output this run can write already exists, before anything is written.
+    /// `contig_kinds` are the kind and extension of each output written per contig and
+    /// `run_kinds` those written once, paged plots (`<kind>.page<N>`) are checked too
+    pub fn check_free(
+        &self,
+        contigs: &[String],
+        contig_kinds: &[(&str, &str)],
+        run_kinds: &[(&str, &str)],
+    ) -> Result<(), String> {
+        if self.force {
+            return Ok(());
+        }
+        let mut stems: Vec<(String, &[(&str, &str)])> = contigs
+            .iter()
+            .map(|contig| {
+                (
+                    format!(
+                        "{}.{}.{}.",
+                        self.prefix,
+                        contig,
+                        region_name(self.start, self.stop)
+                    ),
+                    contig_kinds,
+                )
+            })
+            .collect();
+        stems.push((format!("{}.{}.", self.prefix, self.run_region), run_kinds));
+        let Ok(entries) = fs::read_dir(&self.outdir) else {
+            return Ok(());
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let collides = stems.iter().any(|(stem, kinds)| {
+                kinds
+                    .iter()
+                    .any(|(kind, ext)| output_matches(&name, stem, kind, ext))
+            });
+            if collides {
+                return Err(format!(
+                    "Refusing to overwrite existing file {}, use --force to overwrite",
+                    entry.path().display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 1-based inclusive region used in output names, `<start>-end` when it runs to the contig end
+fn region_name(start: u32, stop: u32) -> String {
+    if stop == u32::MAX {
+        format!("{}-end", start + 1)
+    } else {
+        format!("{}-{}", start + 1, stop)
+    }
+}
+
+/// Whether a file name is `<stem><kind>.<ext>` or a page of it, `<stem><kind>.page<N>.<ext>`
+fn output_matches(name: &str, stem: &str, kind: &str, ext: &str) -> bool {
+    let Some(rest) = name
+        .strip_prefix(stem)
+        .and_then(|rest| rest.strip_prefix(kind))
+        .and_then(|rest| rest.strip_suffix(ext))
+        .and_then(|rest| rest.strip_suffix('.'))
+    else {
+        return false;
+    };
+    match rest.strip_prefix(".page") {
+        Some(page) => !page.is_empty() && page.chars().all(|c| c.is_ascii_digit()),
+        None => rest.is_empty(),
+    }
+}
+
 fn build_index(path: &str) -> Result<(), Box<dyn Error>> {
     match bam::index::build(path, None, bam::index::Type::Bai, 1) {
         Ok(_) => Ok(()),
@@ -33,6 +263,73 @@ fn build_index(path: &str) -> Result<(), Box<dyn Error>> {
     }
 }
 
+/// Read a text file, decompressing it first when it is gzip or bgzip compressed
+pub(crate) fn read_text(path: &str) -> Result<String, String> {
+    let mut bytes = fs::read(path).map_err(|e| e.to_string())?;
+    if bytes.starts_with(&GZIP_MAGIC) {
+        let mut reader = bgzf::Reader::from_path(path).map_err(|e| e.to_string())?;
+        bytes.clear();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("failed to decompress: {}", e))?;
+    }
+    String::from_utf8(bytes).map_err(|_| "not a text file".to_string())
+}
+
+/// Lower case file name without a .gz or .bgz extension, to tell the format of a compressed file
+pub(crate) fn uncompressed_name(path: &str) -> String {
+    let lower = path.to_lowercase();
+    match lower
+        .strip_suffix(".gz")
+        .or_else(|| lower.strip_suffix(".bgz"))
+    {
+        Some(name) => name.to_string(),
+        None => lower,
+    }
+}
+
+/// An interval of a BED file, 0-based and half open, with the name column if present
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BedInterval {
+    pub contig: String,
+    pub start: u32,
+    pub end: u32,
+    pub name: Option<String>,
+}
+
+/// Read every interval of a BED file, skipping comment, track, browser and blank lines
+pub(crate) fn parse_bed(contents: &str) -> Result<Vec<BedInterval>, String> {
+    let mut intervals = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+            || line.trim().is_empty()
+        {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(format!("line {} has fewer than 3 columns", index + 1));
+        }
+        intervals.push(BedInterval {
+            contig: fields[0].to_string(),
+            start: parse_coordinate(fields[1], index, "coordinate")?,
+            end: parse_coordinate(fields[2], index, "coordinate")?,
+            name: fields.get(3).map(|name| name.to_string()),
+        });
+    }
+    Ok(intervals)
+}
+
+/// Parse a coordinate column of a text file, `index` is the 0-based line of the field and
+/// `kind` names it in the error
+pub(crate) fn parse_coordinate(field: &str, index: usize, kind: &str) -> Result<u32, String> {
+    field
+        .parse::<u32>()
+        .map_err(|_| format!("line {} has an invalid {} {}", index + 1, kind, field))
+}
+
 pub fn read_bam(path: &str) -> IndexedReader {
     let index_path = format!("{}.bai", path);
     if !path::Path::new(&index_path).exists() {
@@ -47,8 +344,7 @@ pub fn read_bam(path: &str) -> IndexedReader {
             }
         }
     }
-    let reader = bam::IndexedReader::from_path(path).expect("Failed to open BAM file");
-    reader
+    bam::IndexedReader::from_path(path).expect("Failed to open BAM file")
 }
 
 #[cfg(test)]
@@ -63,9 +359,9 @@ mod tests {
         let temp_path = "testing/util_test.bam";
         let mut header = bam::Header::new();
         header.push_record(
-            &bam::header::HeaderRecord::new(b"SQ")
-                .push_tag(b"SN", &"chr1")
-                .push_tag(b"LN", &1000),
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 1000),
         );
         let header_view = bam::HeaderView::from_header(&header);
         let mut writer = bam::Writer::from_path(temp_path, &header, bam::Format::Bam).unwrap();
@@ -123,14 +419,162 @@ mod tests {
     }
 
     #[rstest]
-    fn test_build_index_success(testbam: bam::Reader) {
+    fn test_plot_supported() {
+        assert_eq!(
+            plot_supported("depth", true),
+            cfg!(any(feature = "plot", feature = "native-plot"))
+        );
+        assert_eq!(plot_supported("strand", false), cfg!(feature = "plot"));
+    }
+
+    #[rstest]
+    fn test_sample_name() {
+        assert_eq!(sample_name("data/sample1.sorted.bam"), "sample1.sorted");
+        assert_eq!(sample_name("sample2"), "sample2");
+    }
+
+    #[rstest]
+    fn test_output_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let outdir = dir.path().join("results");
+        let outputs = OutputPaths::new(outdir.to_str().unwrap(), "sample", false);
+        assert!(outdir.is_dir());
+
+        let path = outputs.contig_path("chr1", 0, u32::MAX, "ambig", "png");
+        assert_eq!(
+            path,
+            outdir.join("sample.chr1.1-end.ambig.png").to_str().unwrap()
+        );
+        let path = outputs.contig_path("chr1", 99, 200, "ambig", "bed");
+        assert_eq!(
+            path,
+            outdir
+                .join("sample.chr1.100-200.ambig.bed")
+                .to_str()
+                .unwrap()
+        );
+        let path = outputs.run_path("manifest", "json");
+        assert_eq!(
+            path,
+            outdir.join("sample.all.manifest.json").to_str().unwrap()
+        );
+        let outputs = outputs.for_region(Some("chr1"), 0, 5);
+        let path = outputs.run_path("manifest", "json");
+        assert_eq!(
+            path,
+            outdir
+                .join("sample.chr1.1-5.manifest.json")
+                .to_str()
+                .unwrap()
+        );
+    }
+
+    #[rstest]
+    fn test_output_paths_no_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let outdir = dir.path().to_str().unwrap();
+        let contigs = vec!["chr1".to_string()];
+        let contig_kinds = [("ambig", "png"), ("ambig", "bed")];
+        let run_kinds = [("manifest", "json")];
+
+        // a different region of the same contig does not collide
+        fs::write(dir.path().join("sample.chr1.1-5.manifest.json"), "").unwrap();
+        fs::write(dir.path().join("sample.chr1.1-5.ambig.bed"), "").unwrap();
+        let outputs = OutputPaths::new(outdir, "sample", false).for_region(Some("chr1"), 5, 10);
+        assert!(outputs
+            .check_free(&contigs, &contig_kinds, &run_kinds)
+            .is_ok());
+
+        // run level and paged contig outputs of the same region do
+        let outputs = OutputPaths::new(outdir, "sample", false).for_region(Some("chr1"), 0, 5);
+        let error = outputs
+            .check_free(&contigs, &contig_kinds, &run_kinds)
+            .unwrap_err();
+        assert!(error.contains("Refusing to overwrite"));
+        fs::remove_file(dir.path().join("sample.chr1.1-5.manifest.json")).unwrap();
+        fs::remove_file(dir.path().join("sample.chr1.1-5.ambig.bed")).unwrap();
+        fs::write(dir.path().join("sample.chr1.1-5.ambig.page2.png"), "").unwrap();
+        assert!(outputs
+            .check_free(&contigs, &contig_kinds, &run_kinds)
+            .is_err());
+    }
+
+    #[rstest]
+    fn test_output_paths_force() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("sample.all.manifest.json"), "").unwrap();
+        let outputs = OutputPaths::new(dir.path().to_str().unwrap(), "sample", true);
+        assert!(outputs
+            .check_free(&[], &[], &[("manifest", "json")])
+            .is_ok());
+    }
+
+    #[rstest]
+    fn test_read_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("sites.bed");
+        fs::write(&plain, "chr1\t10\t20\n").unwrap();
+        assert_eq!(
+            read_text(plain.to_str().unwrap()).unwrap(),
+            "chr1\t10\t20\n"
+        );
+
+        let compressed = dir.path().join("sites.bed.gz");
+        let mut writer = bgzf::Writer::from_path(&compressed).unwrap();
+        std::io::Write::write_all(&mut writer, b"chr1\t10\t20\n").unwrap();
+        drop(writer);
+        assert!(fs::read(&compressed).unwrap().starts_with(&GZIP_MAGIC));
+        assert_eq!(
+            read_text(compressed.to_str().unwrap()).unwrap(),
+            "chr1\t10\t20\n"
+        );
+    }
+
+    #[rstest]
+    fn test_parse_bed() {
+        let bed =
+            "browser position chr1\ntrack name=genes\n# comment\n\nchr1\t9\t200\tS\nchr2\t0\t50\n";
+        let intervals = parse_bed(bed).unwrap();
+        assert_eq!(
+            intervals,
+            vec![
+                BedInterval {
+                    contig: "chr1".to_string(),
+                    start: 9,
+                    end: 200,
+                    name: Some("S".to_string()),
+                },
+                BedInterval {
+                    contig: "chr2".to_string(),
+                    start: 0,
+                    end: 50,
+                    name: None,
+                },
+            ]
+        );
+        assert_eq!(
+            parse_bed("chr1\tstart\t200\n"),
+            Err("line 1 has an invalid coordinate start".to_string())
+        );
+        assert!(parse_bed("chr1\t10\n").is_err());
+    }
+
+    #[rstest]
+    fn test_uncompressed_name() {
+        assert_eq!(uncompressed_name("data/Mask.VCF.gz"), "data/mask.vcf");
+        assert_eq!(uncompressed_name("mask.bed.bgz"), "mask.bed");
+        assert_eq!(uncompressed_name("mask.vcf"), "mask.vcf");
+    }
+
+    #[rstest]
+    fn test_build_index_success(_testbam: bam::Reader) {
         let path = "testing/util_test.bam";
         let result = build_index(path);
         assert!(result.is_ok());
     }
 
     #[rstest]
-    fn test_read_bam(testbam: bam::Reader) {
+    fn test_read_bam(_testbam: bam::Reader) {
         let bam = read_bam("testing/util_test.bam");
         let tid = String::from_utf8(bam.header().target_names()[0].to_vec()).unwrap();
         assert_eq!(tid, "chr1");
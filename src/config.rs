@@ -0,0 +1,574 @@
+use std::fmt;
+use std::fs;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::style::{LegendPosition, Palette, PlotStyle};
+
+/// Reads htslib holds in a pileup column unless told otherwise
+pub const DEFAULT_MAX_DEPTH: u32 = 8000;
+
+/// Built in threshold sets for common sequencing setups
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Preset {
+    /// Short read Illumina data: strict base quality, indels kept
+    Illumina,
+    /// Oxford Nanopore data: relaxed quality, indels ignored
+    Ont,
+    /// Tiled amplicon data: high depth, no strand bias filter
+    Amplicon,
+}
+
+/// Plots drawn for each contig by the ambig command
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlotType {
+    /// Stacked allele proportions at each flagged position
+    Bar,
+    /// Forward and reverse strand allele counts mirrored at each flagged position
+    Strand,
+    /// Flagged positions on a linear genome axis above the depth, with an optional gene track
+    Overview,
+}
+
+impl PlotType {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            PlotType::Bar => "bar",
+            PlotType::Strand => "strand",
+            PlotType::Overview => "overview",
+        }
+    }
+}
+
+/// What the bar heights of the ambig plot show
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlotValues {
+    /// Proportion of the reads counted at the position
+    Proportions,
+    /// Number of reads supporting each allele
+    Counts,
+}
+
+/// How much each read contributes to the allele counts used for proportions
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weighting {
+    /// Every read counts once
+    None,
+    /// Reads are weighted by the probability their base call is correct
+    Bq,
+    /// Reads are weighted by the probability both their base call and alignment are correct
+    BqMq,
+}
+
+/// How --threshold is compared against the minor alleles at a position
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThresholdMode {
+    /// The summed proportion of every minor allele must exceed the threshold
+    Total,
+    /// At least one minor allele must exceed the threshold on its own
+    PerAllele,
+}
+
+/// Which depth is compared against --depth
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DepthMode {
+    /// The pileup depth before any read filtering
+    Raw,
+    /// The reads that contributed an allele after read filtering
+    Filtered,
+}
+
+/// How --dedup-overlaps resolves mates of a pair that cover a position with different alleles
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MateConflict {
+    /// Keep the allele of the mate with the higher base quality
+    Best,
+    /// Count neither mate
+    Discard,
+}
+
+/// Alleles removed inside homopolymers longer than --max-homopolymer
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HomopolymerAlleles {
+    /// Only insertions and deletions are removed
+    Indels,
+    /// Every allele is removed
+    All,
+}
+
+/// What happens to positions listed in the --mask file
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskMode {
+    /// Masked positions are left out of the scan
+    Exclude,
+    /// Masked positions are scanned but fail with the masked reason
+    Flag,
+}
+
+/// The effective settings used to scan for ambiguous bases
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Settings {
+    pub threshold: f64,
+    pub threshold_mode: ThresholdMode,
+    // a non-reference allele must have a frequency within this band, 0 and 1 keep every position
+    pub min_af: f64,
+    pub max_af: f64,
+    pub base_quality_threshold: u8,
+    pub map_quality_threshold: u8,
+    pub depth_threshold: u32,
+    pub depth_mode: DepthMode,
+    // reads htslib holds in a pileup column before dropping new ones, 0 for no limit
+    pub max_depth: u32,
+    // count one base per fragment where the mates of a pair overlap
+    pub dedup_overlaps: bool,
+    pub mate_conflict: MateConflict,
+    // recalculate base qualities with BAQ, needs a reference
+    pub baq: bool,
+    pub strand_bias_threshold: f64,
+    pub no_indel: bool,
+    pub weighting: Weighting,
+    // bases this close to either aligned end of a read are removed, 0 keeps every base
+    pub read_end_distance: u32,
+    // FASTA file used for the sequence context of each position
+    pub reference: Option<String>,
+    // alleles inside homopolymers longer than this are removed, 0 keeps every allele
+    pub max_homopolymer: u32,
+    pub homopolymer_alleles: HomopolymerAlleles,
+    // BED or VCF file of positions to leave out of the scan
+    pub mask: Option<String>,
+    pub mask_mode: MaskMode,
+    pub no_label: bool,
+    pub bed: bool,
+    pub json: bool,
+    pub report_all: bool,
+    pub plot_types: Vec<PlotType>,
+    pub plot_values: PlotValues,
+    // 0 draws every position on one plot
+    pub max_positions_per_plot: usize,
+    // GFF3, GTF or BED file of genes drawn on the overview plot
+    pub annotation: Option<String>,
+    // VCF or TSV file of positions genotyped whether or not they are ambiguous
+    pub sites: Option<String>,
+    pub style: PlotStyle,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            threshold_mode: ThresholdMode::Total,
+            min_af: 0.0,
+            max_af: 1.0,
+            base_quality_threshold: 20,
+            map_quality_threshold: 60,
+            depth_threshold: 100,
+            depth_mode: DepthMode::Raw,
+            max_depth: DEFAULT_MAX_DEPTH,
+            dedup_overlaps: false,
+            mate_conflict: MateConflict::Best,
+            baq: false,
+            strand_bias_threshold: 0.1,
+            no_indel: false,
+            weighting: Weighting::None,
+            read_end_distance: 0,
+            reference: None,
+            max_homopolymer: 0,
+            homopolymer_alleles: HomopolymerAlleles::Indels,
+            mask: None,
+            mask_mode: MaskMode::Exclude,
+            no_label: false,
+            bed: false,
+            json: false,
+            report_all: false,
+            plot_types: vec![PlotType::Bar],
+            plot_values: PlotValues::Proportions,
+            max_positions_per_plot: 200,
+            annotation: None,
+            sites: None,
+            style: PlotStyle::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_preset(preset: Preset) -> Self {
+        match preset {
+            Preset::Illumina => Self {
+                threshold: 0.1,
+                base_quality_threshold: 30,
+                map_quality_threshold: 60,
+                depth_threshold: 100,
+                strand_bias_threshold: 0.1,
+                no_indel: false,
+                ..Self::default()
+            },
+            Preset::Ont => Self {
+                threshold: 0.2,
+                base_quality_threshold: 10,
+                map_quality_threshold: 30,
+                depth_threshold: 50,
+                strand_bias_threshold: 0.1,
+                no_indel: true,
+                ..Self::default()
+            },
+            Preset::Amplicon => Self {
+                threshold: 0.1,
+                base_quality_threshold: 20,
+                map_quality_threshold: 60,
+                depth_threshold: 500,
+                strand_bias_threshold: 0.0,
+                no_indel: false,
+                ..Self::default()
+            },
+        }
+    }
+
+    /// Check settings that depend on each other, as the config file can set either one
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_af > self.max_af {
+            return Err(format!(
+                "The minimum allele frequency {} must not be above the maximum {}",
+                self.min_af, self.max_af
+            ));
+        }
+        if self.baq && self.reference.is_none() {
+            return Err("baq needs a reference to realign reads against".to_string());
+        }
+        if self.max_homopolymer > 0 && self.reference.is_none() {
+            return Err("max_homopolymer needs a reference to find homopolymers".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether --min-af or --max-af narrow the allele frequency band
+    pub fn has_af_band(&self) -> bool {
+        self.min_af > 0.0 || self.max_af < 1.0
+    }
+
+    /// Overwrite any settings that are present in the config file
+    pub fn apply_config(&mut self, config: &Config) {
+        if let Some(threshold) = config.threshold {
+            self.threshold = threshold;
+        }
+        if let Some(threshold_mode) = config.threshold_mode {
+            self.threshold_mode = threshold_mode;
+        }
+        if let Some(min_af) = config.min_af {
+            self.min_af = min_af;
+        }
+        if let Some(max_af) = config.max_af {
+            self.max_af = max_af;
+        }
+        if let Some(base_quality_threshold) = config.base_quality_threshold {
+            self.base_quality_threshold = base_quality_threshold;
+        }
+        if let Some(map_quality_threshold) = config.map_quality_threshold {
+            self.map_quality_threshold = map_quality_threshold;
+        }
+        if let Some(depth_threshold) = config.depth_threshold {
+            self.depth_threshold = depth_threshold;
+        }
+        if let Some(depth_mode) = config.depth_mode {
+            self.depth_mode = depth_mode;
+        }
+        if let Some(max_depth) = config.max_depth {
+            self.max_depth = max_depth;
+        }
+        if let Some(dedup_overlaps) = config.dedup_overlaps {
+            self.dedup_overlaps = dedup_overlaps;
+        }
+        if let Some(mate_conflict) = config.mate_conflict {
+            self.mate_conflict = mate_conflict;
+        }
+        if let Some(baq) = config.baq {
+            self.baq = baq;
+        }
+        if let Some(strand_bias_threshold) = config.strand_bias_threshold {
+            self.strand_bias_threshold = strand_bias_threshold;
+        }
+        if let Some(no_indel) = config.no_indel {
+            self.no_indel = no_indel;
+        }
+        if let Some(weighting) = config.weighting {
+            self.weighting = weighting;
+        }
+        if let Some(read_end_distance) = config.read_end_distance {
+            self.read_end_distance = read_end_distance;
+        }
+        if let Some(reference) = &config.reference {
+            self.reference = Some(reference.clone());
+        }
+        if let Some(max_homopolymer) = config.max_homopolymer {
+            self.max_homopolymer = max_homopolymer;
+        }
+        if let Some(homopolymer_alleles) = config.homopolymer_alleles {
+            self.homopolymer_alleles = homopolymer_alleles;
+        }
+        if let Some(mask) = &config.mask {
+            self.mask = Some(mask.clone());
+        }
+        if let Some(mask_mode) = config.mask_mode {
+            self.mask_mode = mask_mode;
+        }
+        if let Some(no_label) = config.no_label {
+            self.no_label = no_label;
+        }
+        if let Some(bed) = config.bed {
+            self.bed = bed;
+        }
+        if let Some(json) = config.json {
+            self.json = json;
+        }
+        if let Some(report_all) = config.report_all {
+            self.report_all = report_all;
+        }
+        if let Some(plot_types) = &config.plot_types {
+            self.plot_types = plot_types.clone();
+        }
+        if let Some(plot_values) = config.plot_values {
+            self.plot_values = plot_values;
+        }
+        if let Some(max_positions_per_plot) = config.max_positions_per_plot {
+            self.max_positions_per_plot = max_positions_per_plot;
+        }
+        if let Some(annotation) = &config.annotation {
+            self.annotation = Some(annotation.clone());
+        }
+        if let Some(sites) = &config.sites {
+            self.sites = Some(sites.clone());
+        }
+        if let Some(title) = &config.title {
+            self.style.title = Some(title.clone());
+        }
+        if let Some(palette) = config.palette {
+            self.style.palette = palette;
+        }
+        if let Some(font_size) = config.font_size {
+            self.style.font_size = Some(font_size);
+        }
+        if let Some(legend) = config.legend {
+            self.style.legend = legend;
+        }
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "threshold={} min-BQ={} min-MQ={} depth={} strand-bias={} no-indel={}",
+            self.threshold,
+            self.base_quality_threshold,
+            self.map_quality_threshold,
+            self.depth_threshold,
+            self.strand_bias_threshold,
+            self.no_indel
+        )?;
+        if self.depth_mode != DepthMode::Raw {
+            write!(
+                f,
+                " depth-mode={}",
+                self.depth_mode.to_possible_value().unwrap().get_name()
+            )?;
+        }
+        if self.max_depth != DEFAULT_MAX_DEPTH {
+            write!(f, " max-depth={}", self.max_depth)?;
+        }
+        if self.dedup_overlaps {
+            write!(
+                f,
+                " dedup-overlaps=true mate-conflict={}",
+                self.mate_conflict.to_possible_value().unwrap().get_name()
+            )?;
+        }
+        if self.baq {
+            write!(f, " baq=true")?;
+        }
+        if self.threshold_mode != ThresholdMode::Total {
+            write!(
+                f,
+                " threshold-mode={}",
+                self.threshold_mode.to_possible_value().unwrap().get_name()
+            )?;
+        }
+        if self.has_af_band() {
+            write!(f, " af={}-{}", self.min_af, self.max_af)?;
+        }
+        if self.weighting != Weighting::None {
+            write!(
+                f,
+                " weighting={}",
+                self.weighting.to_possible_value().unwrap().get_name()
+            )?;
+        }
+        if self.read_end_distance > 0 {
+            write!(f, " read-end={}", self.read_end_distance)?;
+        }
+        if self.max_homopolymer > 0 {
+            write!(
+                f,
+                " max-homopolymer={} homopolymer-alleles={}",
+                self.max_homopolymer,
+                self.homopolymer_alleles
+                    .to_possible_value()
+                    .unwrap()
+                    .get_name()
+            )?;
+        }
+        if self.mask.is_some() {
+            write!(
+                f,
+                " mask={}",
+                self.mask_mode.to_possible_value().unwrap().get_name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The pileup settings of the depth command, which has no read filters
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DepthSettings {
+    pub max_depth: u32,
+    pub dedup_overlaps: bool,
+}
+
+impl Default for DepthSettings {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            dedup_overlaps: false,
+        }
+    }
+}
+
+impl fmt::Display for DepthSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "max-depth={} dedup-overlaps={}",
+            self.max_depth, self.dedup_overlaps
+        )
+    }
+}
+
+/// The settings recorded in the provenance of a run, which depend on the command
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum RunSettings {
+    Scan(Box<Settings>),
+    Depth(DepthSettings),
+}
+
+impl fmt::Display for RunSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunSettings::Scan(settings) => settings.fmt(f),
+            RunSettings::Depth(settings) => settings.fmt(f),
+        }
+    }
+}
+
+/// Settings read from a TOML config file, missing keys fall back to the preset or defaults
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub threshold: Option<f64>,
+    pub threshold_mode: Option<ThresholdMode>,
+    pub min_af: Option<f64>,
+    pub max_af: Option<f64>,
+    pub base_quality_threshold: Option<u8>,
+    pub map_quality_threshold: Option<u8>,
+    pub depth_threshold: Option<u32>,
+    pub depth_mode: Option<DepthMode>,
+    pub max_depth: Option<u32>,
+    pub dedup_overlaps: Option<bool>,
+    pub mate_conflict: Option<MateConflict>,
+    pub baq: Option<bool>,
+    pub strand_bias_threshold: Option<f64>,
+    pub no_indel: Option<bool>,
+    pub weighting: Option<Weighting>,
+    pub read_end_distance: Option<u32>,
+    pub reference: Option<String>,
+    pub max_homopolymer: Option<u32>,
+    pub homopolymer_alleles: Option<HomopolymerAlleles>,
+    pub mask: Option<String>,
+    pub mask_mode: Option<MaskMode>,
+    pub no_label: Option<bool>,
+    pub bed: Option<bool>,
+    pub json: Option<bool>,
+    pub report_all: Option<bool>,
+    pub plot_types: Option<Vec<PlotType>>,
+    pub plot_values: Option<PlotValues>,
+    pub max_positions_per_plot: Option<usize>,
+    pub annotation: Option<String>,
+    pub sites: Option<String>,
+    pub title: Option<String>,
+    pub palette: Option<Palette>,
+    // colour file, read when the settings are resolved
+    pub colours: Option<String>,
+    pub font_size: Option<usize>,
+    pub legend: Option<LegendPosition>,
+}
+
+impl Config {
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| format!("Invalid config file {}: {}", path, e))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for threshold in [config.threshold, config.strand_bias_threshold]
+            .iter()
+            .flatten()
+        {
+            if !(0.0..=0.5).contains(threshold) {
+                return Err("Threshold must be between 0 and 0.5".to_string());
+            }
+        }
+        for frequency in [config.min_af, config.max_af].iter().flatten() {
+            if !(0.0..=1.0).contains(frequency) {
+                return Err("Allele frequency must be between 0 and 1".to_string());
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse("threshold = 0.3\nno_indel = true\n").unwrap();
+        let mut settings = Settings::from_preset(Preset::Ont);
+        settings.apply_config(&config);
+        assert_eq!(settings.threshold, 0.3);
+        assert!(settings.no_indel);
+        assert_eq!(settings.base_quality_threshold, 10);
+
+        let config = Config::parse("palette = \"greyscale\"\nlegend = \"top\"\n").unwrap();
+        settings.apply_config(&config);
+        assert_eq!(settings.style.palette, Palette::Greyscale);
+        assert_eq!(settings.style.legend, LegendPosition::Top);
+    }
+
+    #[test]
+    fn test_parse_config_invalid() {
+        assert!(Config::parse("threshold = 0.8\n").is_err());
+        assert!(Config::parse("max_af = 1.5\n").is_err());
+        assert!(Config::parse("min_depth = 10\n").is_err());
+    }
+}
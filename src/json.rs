@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+
+use serde::Serialize;
+
+use crate::commands::ambig::{
+    ContigScan, ContigSummary, FixedDifference, PositionResult, ReadFilterCounts,
+};
+use crate::provenance::Provenance;
+use crate::reference::SequenceContext;
+
+/// Bumped whenever a field is removed or changes meaning, adding fields does not bump the version
+pub const SCHEMA_VERSION: u32 = 1;
+
+const ALLELES: [char; 6] = ['A', 'C', 'G', 'T', '-', '+'];
+
+/// Top level document written by `ambig --json`, described by `schemas/ambig.schema.json`
+#[derive(Serialize)]
+pub struct AmbigJson<'a> {
+    schema: &'static str,
+    schema_version: u32,
+    run: &'a Provenance,
+    contigs: Vec<AmbigContig>,
+}
+
+#[derive(Serialize)]
+pub struct AmbigContig {
+    name: String,
+    summary: ContigSummary,
+    positions: Vec<PositionJson>,
+    // only present with --reference
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fixed_differences: Option<Vec<FixedDifference>>,
+}
+
+#[derive(Serialize)]
+struct PositionJson {
+    pos: u32,
+    depth: u32,
+    filtered_depth: u32,
+    total: u32,
+    major: char,
+    minor_proportion: f64,
+    // most common minor allele that passed the strand bias check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    second_allele: Option<char>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    second_proportion: Option<f64>,
+    allele_count: u32,
+    multiallelic: bool,
+    passed: bool,
+    reasons: Vec<&'static str>,
+    reads_removed: ReadFilterCounts,
+    // overlapping pairs whose mates disagree, only counted with --dedup-overlaps
+    mate_conflicts: u32,
+    // summed read weights, only present with --weighting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weighted_total: Option<f64>,
+    // reference sequence context, only present with --reference
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<SequenceContext>,
+    alleles: BTreeMap<char, AlleleJson>,
+}
+
+#[derive(Serialize)]
+struct AlleleJson {
+    count: u32,
+    forward: u32,
+    reverse: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weighted_count: Option<f64>,
+    proportion: f64,
+    strand_bias_pass: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mean_end_distance: Option<f64>,
+    // only present for alleles other than the major allele
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_pos_rank_sum: Option<f64>,
+}
+
+impl<'a> AmbigJson<'a> {
+    pub fn new(run: &'a Provenance) -> Self {
+        Self {
+            schema: "ambigviz/ambig",
+            schema_version: SCHEMA_VERSION,
+            run,
+            contigs: Vec::new(),
+        }
+    }
+
+    /// Add a contig, failed positions are only listed when the scan kept them with --report-all
+    pub fn add_contig(&mut self, name: &str, scan: &ContigScan) {
+        let positions: Vec<PositionJson> = scan.positions.iter().map(PositionJson::from).collect();
+        self.contigs.push(AmbigContig {
+            name: name.to_string(),
+            summary: scan.summary(),
+            positions,
+            fixed_differences: scan.fixed_differences.clone(),
+        });
+    }
+
+    pub fn write(&self, path: &str) {
+        write_json(path, self);
+    }
+}
+
+impl From<&PositionResult> for PositionJson {
+    fn from(result: &PositionResult) -> Self {
+        let total = result.pileup.get_total();
+        let weighted = result.pileup.is_weighted();
+        let alleles = ALLELES
+            .iter()
+            .filter(|allele| result.pileup.get_base_count(**allele) > 0)
+            .map(|allele| {
+                let count = result.pileup.get_base_count(*allele);
+                let (forward, reverse) = result.pileup.get_strand_counts(*allele);
+                let proportion = result.pileup.get_proportion(*allele);
+                (
+                    *allele,
+                    AlleleJson {
+                        count,
+                        forward,
+                        reverse,
+                        weighted_count: weighted
+                            .then(|| (result.pileup.get_weight(*allele) * 100.0).round() / 100.0),
+                        proportion: (proportion * 10000.0).round() / 10000.0,
+                        strand_bias_pass: !result.strand_failed.contains(allele),
+                        mean_end_distance: result
+                            .pileup
+                            .mean_end_distance(*allele)
+                            .map(|mean| (mean * 100.0).round() / 100.0),
+                        read_pos_rank_sum: result
+                            .pileup
+                            .read_pos_rank_sum(*allele, result.major)
+                            .map(|z| (z * 1000.0).round() / 1000.0),
+                    },
+                )
+            })
+            .collect();
+        Self {
+            pos: result.pos,
+            depth: result.depth,
+            filtered_depth: result.filtered_depth,
+            total,
+            major: result.major,
+            minor_proportion: (result.minor_proportion * 10000.0).round() / 10000.0,
+            second_allele: result.second_allele().map(|(allele, _)| allele),
+            second_proportion: result
+                .second_allele()
+                .map(|(_, proportion)| (proportion * 10000.0).round() / 10000.0),
+            allele_count: result.allele_count,
+            multiallelic: result.is_multiallelic(),
+            passed: result.passed,
+            reasons: result
+                .reasons
+                .iter()
+                .map(|reason| reason.as_str())
+                .collect(),
+            reads_removed: result.removed.clone(),
+            mate_conflicts: result.mate_conflicts,
+            weighted_total: weighted
+                .then(|| (result.pileup.get_total_weight() * 100.0).round() / 100.0),
+            context: result.context.clone(),
+            alleles,
+        }
+    }
+}
+
+/// Top level document written by `depth --json`, described by `schemas/depth.schema.json`
+#[derive(Serialize)]
+pub struct DepthJson<'a> {
+    schema: &'static str,
+    schema_version: u32,
+    run: &'a Provenance,
+    contigs: Vec<DepthContig>,
+}
+
+#[derive(Serialize)]
+struct DepthContig {
+    name: String,
+    summary: DepthSummary,
+    positions: Vec<DepthPosition>,
+}
+
+#[derive(Serialize)]
+struct DepthSummary {
+    positions: u32,
+    min_depth: u32,
+    max_depth: u32,
+    mean_depth: f64,
+}
+
+#[derive(Serialize)]
+struct DepthPosition {
+    pos: u32,
+    depth: u32,
+}
+
+impl<'a> DepthJson<'a> {
+    pub fn new(run: &'a Provenance) -> Self {
+        Self {
+            schema: "ambigviz/depth",
+            schema_version: SCHEMA_VERSION,
+            run,
+            contigs: Vec::new(),
+        }
+    }
+
+    /// Add a contig from 1-based positions and their depths
+    pub fn add_contig(&mut self, name: &str, x: &[u32], y: &[u32]) {
+        let mean_depth = if y.is_empty() {
+            0.0
+        } else {
+            y.iter().map(|depth| *depth as f64).sum::<f64>() / y.len() as f64
+        };
+        self.contigs.push(DepthContig {
+            name: name.to_string(),
+            summary: DepthSummary {
+                positions: y.len() as u32,
+                min_depth: y.iter().cloned().min().unwrap_or(0),
+                max_depth: y.iter().cloned().max().unwrap_or(0),
+                mean_depth: (mean_depth * 100.0).round() / 100.0,
+            },
+            positions: x
+                .iter()
+                .zip(y)
+                .map(|(pos, depth)| DepthPosition {
+                    pos: *pos,
+                    depth: *depth,
+                })
+                .collect(),
+        });
+    }
+
+    pub fn write(&self, path: &str) {
+        write_json(path, self);
+    }
+}
+
+fn write_json<T: Serialize>(path: &str, value: &T) {
+    let file = File::create(path).expect("Failed to create file");
+    serde_json::to_writer_pretty(file, value).expect("Failed to write to file");
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::commands::ambig::Pileup;
+
+    /// Check every key the schema marks as required is present in the value
+    fn assert_required(schema: &Value, value: &Value) {
+        for key in schema["required"].as_array().unwrap() {
+            let key = key.as_str().unwrap();
+            assert!(value.get(key).is_some(), "missing required key {}", key);
+        }
+    }
+
+    fn provenance() -> Provenance {
+        Provenance::new(vec!["sample.bam".to_string()], Some("chr1"), 0, 10, None)
+    }
+
+    #[test]
+    fn test_ambig_json_matches_schema() {
+        let schema: Value =
+            serde_json::from_str(include_str!("../schemas/ambig.schema.json")).unwrap();
+        let mut pileup = Pileup::new();
+        pileup.a = (6, (3, 3));
+        pileup.g = (4, (4, 0));
+        let scan = ContigScan {
+            positions_scanned: 10,
+            positions_covered: 10,
+            positions_ambiguous: 1,
+            positions_masked: 0,
+            fixed_differences: None,
+            reads_removed: ReadFilterCounts::default(),
+            overlapping_pairs: 0,
+            mate_conflicts: 0,
+            depths: Vec::new(),
+            positions: vec![PositionResult {
+                pos: 5,
+                depth: 12,
+                filtered_depth: 10,
+                pileup,
+                major: 'A',
+                strand_failed: vec!['G'],
+                proportions: BTreeMap::from([('A', 0.6)]),
+                minor_proportion: 0.0,
+                allele_count: 1,
+                passed: true,
+                reasons: Vec::new(),
+                removed: ReadFilterCounts::default(),
+                mate_conflicts: 0,
+                context: None,
+            }],
+        };
+        let run = provenance();
+        let mut json = AmbigJson::new(&run);
+        json.add_contig("chr1", &scan);
+        let value = serde_json::to_value(&json).unwrap();
+
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+        assert_required(&schema, &value);
+        let contig = &value["contigs"][0];
+        assert_required(&schema["$defs"]["contig"], contig);
+        let position = &contig["positions"][0];
+        assert_required(&schema["$defs"]["position"], position);
+        let allele = &position["alleles"]["G"];
+        assert_required(&schema["$defs"]["allele"], allele);
+        assert_eq!(allele["proportion"], 0.4);
+        assert_eq!(allele["strand_bias_pass"], false);
+    }
+
+    #[test]
+    fn test_depth_json_matches_schema() {
+        let schema: Value =
+            serde_json::from_str(include_str!("../schemas/depth.schema.json")).unwrap();
+        let run = provenance();
+        let mut json = DepthJson::new(&run);
+        json.add_contig("chr1", &[5, 6, 7], &[4, 6, 8]);
+        let value = serde_json::to_value(&json).unwrap();
+
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+        assert_required(&schema, &value);
+        let contig = &value["contigs"][0];
+        assert_required(&schema["$defs"]["contig"], contig);
+        assert_eq!(contig["summary"]["mean_depth"], 6.0);
+        assert_required(&schema["$defs"]["position"], &contig["positions"][0]);
+    }
+}
@@ -2,8 +2,19 @@ use clap::Parser;
 
 pub use crate::cli::Cli;
 
+#[cfg(feature = "plot")]
+mod annotation;
 mod cli;
 pub mod commands;
+mod config;
+mod json;
+mod mask;
+mod provenance;
+mod reference;
+#[cfg(feature = "native-plot")]
+mod render;
+mod sites;
+mod style;
 
 fn main() {
     let args = Cli::parse();
@@ -12,31 +23,85 @@ fn main() {
             input,
             region,
             output,
+            outputs,
+            style,
+            json,
+            max_depth,
+            dedup_overlaps,
         } => {
+            let style = style.resolve().unwrap_or_else(|e| cli::exit_with_error(e));
+            let prefix = output.unwrap_or_else(|| commands::utils::sample_name(&input));
+            let output = commands::utils::OutputPaths::new(&outputs.outdir, &prefix, outputs.force);
             if let Some(region) = region {
                 let region_string = cli::parse_region(&region);
                 let (chrom, start, stop) = region_string;
-                let depth_plotter =
-                    commands::depth::Depth::new(&input, Some(chrom), Some(start), stop, output);
-                depth_plotter.run();
+                let depth_plotter = commands::depth::Depth::new(
+                    &input,
+                    Some(chrom),
+                    Some(start),
+                    stop,
+                    output,
+                    json,
+                    style,
+                )
+                .pileup_options(max_depth, dedup_overlaps);
+                depth_plotter.run().unwrap_or_else(|e| exit_on_error(e));
             } else {
-                let depth_plotter = commands::depth::Depth::new(&input, None, None, None, output);
-                depth_plotter.run();
+                let depth_plotter =
+                    commands::depth::Depth::new(&input, None, None, None, output, json, style)
+                        .pileup_options(max_depth, dedup_overlaps);
+                depth_plotter.run().unwrap_or_else(|e| exit_on_error(e));
             }
         }
         cli::Command::Ambig {
             input,
             region,
-            no_indel,
-            threshold,
+            filters,
+            style,
             no_label,
+            label,
             output,
-            base_quality_threshold,
-            map_quality_threshold,
-            depth_threshold,
-            strand_bias_threshold,
+            outputs,
             bed,
+            no_bed,
+            json,
+            no_json,
+            report_all,
+            no_report_all,
+            plot_types,
+            plot_values,
+            max_positions_per_plot,
+            annotation,
+            sites,
         } => {
+            let mut settings = filters
+                .resolve()
+                .unwrap_or_else(|e| cli::exit_with_error(e));
+            cli::apply_flag(&mut settings.no_label, no_label, label);
+            cli::apply_flag(&mut settings.bed, bed, no_bed);
+            cli::apply_flag(&mut settings.json, json, no_json);
+            cli::apply_flag(&mut settings.report_all, report_all, no_report_all);
+            style
+                .apply(&mut settings.style)
+                .unwrap_or_else(|e| cli::exit_with_error(e));
+            if let Some(plot_types) = plot_types {
+                settings.plot_types = plot_types;
+            }
+            if let Some(plot_values) = plot_values {
+                settings.plot_values = plot_values;
+            }
+            if let Some(max_positions_per_plot) = max_positions_per_plot {
+                settings.max_positions_per_plot = max_positions_per_plot;
+            }
+            if annotation.is_some() {
+                settings.annotation = annotation;
+            }
+            if sites.is_some() {
+                settings.sites = sites;
+            }
+            let prefix = output.unwrap_or_else(|| commands::utils::sample_name(&input));
+            let output = commands::utils::OutputPaths::new(&outputs.outdir, &prefix, outputs.force);
+            println!("Settings: {}", settings);
             if let Some(region) = region {
                 let region_string = cli::parse_region(&region);
                 let (chrom, start, stop) = region_string;
@@ -45,40 +110,118 @@ fn main() {
                     Some(chrom),
                     Some(start),
                     stop,
-                    no_indel,
-                    threshold,
-                    no_label,
+                    settings,
                     output,
-                    base_quality_threshold,
-                    map_quality_threshold,
-                    depth_threshold,
-                    strand_bias_threshold,
-                    bed,
-                );
-                _plotter.run();
+                )
+                .unwrap_or_else(|e| exit_on_error(e));
+                _plotter.run().unwrap_or_else(|e| exit_on_error(e));
             } else {
                 println!("No region specified");
-                let ambig_plotter = commands::ambig::Ambig::new(
-                    &input,
-                    None,
-                    None,
-                    None,
-                    no_indel,
-                    threshold,
-                    no_label,
-                    output,
-                    base_quality_threshold,
-                    map_quality_threshold,
-                    depth_threshold,
-                    strand_bias_threshold,
-                    bed,
-                );
-                ambig_plotter.run();
+                let ambig_plotter =
+                    commands::ambig::Ambig::new(&input, None, None, None, settings, output)
+                        .unwrap_or_else(|e| exit_on_error(e));
+                ambig_plotter.run().unwrap_or_else(|e| exit_on_error(e));
+            }
+        }
+        cli::Command::Report {
+            input,
+            region,
+            output,
+            outputs,
+            filters,
+            style,
+            report_all,
+            no_report_all,
+        } => {
+            let mut settings = filters
+                .resolve()
+                .unwrap_or_else(|e| cli::exit_with_error(e));
+            cli::apply_flag(&mut settings.report_all, report_all, no_report_all);
+            style
+                .apply(&mut settings.style)
+                .unwrap_or_else(|e| cli::exit_with_error(e));
+            let prefix = output.unwrap_or_else(|| commands::utils::sample_name(&input));
+            let output = commands::utils::OutputPaths::new(&outputs.outdir, &prefix, outputs.force);
+            let (chrom, start, stop) = match &region {
+                Some(region) => {
+                    let (chrom, start, stop) = cli::parse_region(region);
+                    (Some(chrom), Some(start), stop)
+                }
+                None => (None, None, None),
+            };
+            println!("Settings: {}", settings);
+            let depth = commands::depth::Depth::new(
+                &input,
+                chrom,
+                start,
+                stop,
+                output.clone(),
+                false,
+                settings.style.clone(),
+            )
+            .pileup_options(settings.max_depth, settings.dedup_overlaps);
+            let ambig =
+                commands::ambig::Ambig::new(&input, chrom, start, stop, settings, output.clone())
+                    .unwrap_or_else(|e| exit_on_error(e));
+            #[cfg(feature = "html")]
+            commands::report::Report::new(ambig, depth, output)
+                .run()
+                .unwrap_or_else(|e| exit_on_error(e));
+            #[cfg(not(feature = "html"))]
+            {
+                let _ = (ambig, depth, output);
+                eprintln!("The report command needs ambigviz built with the html feature");
+                std::process::exit(1);
             }
         }
+        cli::Command::Compare {
+            inputs,
+            region,
+            labels,
+            output,
+            outputs,
+            change_threshold,
+            filters,
+        } => {
+            let output = commands::utils::OutputPaths::new(&outputs.outdir, &output, outputs.force);
+            let (chrom, start, stop) = match &region {
+                Some(region) => {
+                    let (chrom, start, stop) = cli::parse_region(region);
+                    (Some(chrom), Some(start), stop)
+                }
+                None => (None, None, None),
+            };
+            let settings = filters
+                .resolve()
+                .unwrap_or_else(|e| cli::exit_with_error(e));
+            println!("Settings: {}", settings);
+            let samples = inputs
+                .iter()
+                .map(|input| {
+                    commands::ambig::Ambig::new(
+                        input,
+                        chrom,
+                        start,
+                        stop,
+                        settings.clone(),
+                        output.clone(),
+                    )
+                })
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap_or_else(|e| exit_on_error(e));
+            let comparer =
+                commands::compare::Compare::new(samples, labels, change_threshold, output);
+            comparer.run().unwrap_or_else(|e| exit_on_error(e));
+        }
     }
 }
 
+/// Print an error that stopped a command and exit
+fn exit_on_error(error: String) -> ! {
+    eprintln!("error: {}", error);
+    std::process::exit(1);
+}
+
 //todo
 // 1. Output nice tsv
 // 2. Tests
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+use crate::commands::utils::{
+    parse_bed, parse_coordinate, read_text, uncompressed_name, BedInterval,
+};
+
+/// Positions to leave out of the ambiguity scan, read from a BED or VCF file
+#[derive(Debug, Default)]
+pub struct Mask {
+    // sorted, non-overlapping 0-based half open intervals for each contig
+    intervals: BTreeMap<String, Vec<(u32, u32)>>,
+}
+
+impl Mask {
+    /// Read every interval of a BED file, or every record of a VCF file across its REF allele.
+    /// Either may be gzip or bgzip compressed
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let contents =
+            read_text(path).map_err(|e| format!("Failed to read mask file {}: {}", path, e))?;
+        let intervals = if uncompressed_name(path).ends_with(".vcf") {
+            parse_vcf(&contents)
+        } else {
+            parse_bed(&contents)
+        };
+        intervals
+            .map(Self::from_intervals)
+            .map_err(|e| format!("Invalid mask file {}: {}", path, e))
+    }
+
+    fn from_intervals(intervals: Vec<BedInterval>) -> Self {
+        let mut by_contig: BTreeMap<String, Vec<(u32, u32)>> = BTreeMap::new();
+        for interval in intervals {
+            by_contig
+                .entry(interval.contig)
+                .or_default()
+                .push((interval.start, interval.end));
+        }
+        // merge overlapping intervals so a lookup only has to check one
+        for intervals in by_contig.values_mut() {
+            intervals.sort();
+            let mut merged: Vec<(u32, u32)> = Vec::with_capacity(intervals.len());
+            for (start, end) in intervals.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *intervals = merged;
+        }
+        Self {
+            intervals: by_contig,
+        }
+    }
+
+    /// Whether a 0-based position is masked
+    pub fn contains(&self, contig: &str, pos: u32) -> bool {
+        let Some(intervals) = self.intervals.get(contig) else {
+            return false;
+        };
+        let index = intervals.partition_point(|(start, _)| *start <= pos);
+        index > 0 && pos < intervals[index - 1].1
+    }
+}
+
+fn parse_vcf(contents: &str) -> Result<Vec<BedInterval>, String> {
+    let mut intervals = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            return Err(format!("line {} has fewer than 4 columns", index + 1));
+        }
+        // VCF positions are 1-based, the REF allele covers every base a deletion removes
+        let start = parse_coordinate(fields[1], index, "coordinate")?.saturating_sub(1);
+        intervals.push(BedInterval {
+            contig: fields[0].to_string(),
+            start,
+            end: start + fields[3].len().max(1) as u32,
+            name: None,
+        });
+    }
+    Ok(intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bed_mask() {
+        let bed = "track name=mask\nchr1\t10\t20\tprimer\nchr1\t15\t25\nchr1\t40\t41\n";
+        let mask = Mask::from_intervals(parse_bed(bed).unwrap());
+        assert_eq!(mask.intervals["chr1"], vec![(10, 25), (40, 41)]);
+        assert!(!mask.contains("chr1", 9));
+        assert!(mask.contains("chr1", 10));
+        assert!(mask.contains("chr1", 24));
+        assert!(!mask.contains("chr1", 25));
+        assert!(mask.contains("chr1", 40));
+        assert!(!mask.contains("chr2", 10));
+        assert!(parse_bed("chr1\t10\n").is_err());
+    }
+
+    #[test]
+    fn test_vcf_mask() {
+        let vcf = "##fileformat=VCFv4.2\n\
+                   #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+                   chr1\t5\t.\tA\tG\t.\t.\t.\n\
+                   chr1\t100\t.\tACG\tA\t.\t.\t.\n";
+        let mask = Mask::from_intervals(parse_vcf(vcf).unwrap());
+        assert!(mask.contains("chr1", 4));
+        assert!(!mask.contains("chr1", 5));
+        assert!(mask.contains("chr1", 101));
+        assert!(!mask.contains("chr1", 102));
+        assert!(parse_vcf("chr1\tfive\t.\tA\n").is_err());
+    }
+
+    #[test]
+    fn test_compressed_vcf_mask() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mask.vcf.gz");
+        let mut writer = rust_htslib::bgzf::Writer::from_path(&path).unwrap();
+        std::io::Write::write_all(&mut writer, b"#CHROM\tPOS\tID\tREF\nchr1\t5\t.\tACG\n").unwrap();
+        drop(writer);
+        let mask = Mask::from_path(path.to_str().unwrap()).unwrap();
+        assert_eq!(mask.intervals["chr1"], vec![(4, 7)]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::File;
+
+#[cfg(feature = "plot")]
+use plotly::common::Anchor;
+#[cfg(feature = "plot")]
+use plotly::layout::Annotation;
+use serde::Serialize;
+
+use crate::config::RunSettings;
+
+/// Records how a set of outputs was produced so results can be audited later
+#[derive(Debug, Clone, Serialize)]
+pub struct Provenance {
+    pub version: String,
+    pub command_line: String,
+    pub inputs: Vec<String>,
+    pub region: String,
+    pub settings: Option<RunSettings>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    #[serde(flatten)]
+    provenance: &'a Provenance,
+    outputs: &'a [String],
+}
+
+impl Provenance {
+    pub fn new(
+        inputs: Vec<String>,
+        chrom: Option<&str>,
+        start: u32,
+        stop: u32,
+        settings: Option<RunSettings>,
+    ) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            command_line: std::env::args().collect::<Vec<String>>().join(" "),
+            inputs,
+            region: format_region(chrom, start, stop),
+            settings,
+        }
+    }
+
+    /// One line summary used as a plot subtitle
+    pub fn subtitle(&self) -> String {
+        let mut subtitle = format!(
+            "ambigviz v{} | {} | {}",
+            self.version,
+            self.inputs.join(", "),
+            self.region
+        );
+        if let Some(settings) = &self.settings {
+            subtitle.push_str(&format!(" | {}", settings));
+        }
+        subtitle
+    }
+
+    /// The full command line, placed below the plot area
+    #[cfg(feature = "plot")]
+    pub fn annotation(&self) -> Annotation {
+        Annotation::new()
+            .text(format!("<sub>{}</sub>", self.command_line).as_str())
+            .x_ref("paper")
+            .y_ref("paper")
+            .x(0.0)
+            .y(-0.1)
+            .x_anchor(Anchor::Left)
+            .show_arrow(false)
+    }
+
+    /// Key value lines for the comment header of text outputs, without the comment prefix
+    pub fn header_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("ambigviz_version={}", self.version),
+            format!("command={}", self.command_line),
+            format!("input={}", self.inputs.join(",")),
+            format!("region={}", self.region),
+        ];
+        if let Some(settings) = &self.settings {
+            let settings = serde_json::to_string(settings).expect("Failed to serialise settings");
+            lines.push(format!("settings={}", settings));
+        }
+        lines
+    }
+
+    pub fn bed_track_line(&self, name: &str) -> String {
+        format!(
+            "track name=\"{}\" description=\"{}\"",
+            name,
+            self.subtitle().replace('"', "'")
+        )
+    }
+
+    /// Write a JSON manifest listing every output produced by the run
+    pub fn write_manifest(&self, path: &str, outputs: &[String]) {
+        let manifest = Manifest {
+            provenance: self,
+            outputs,
+        };
+        let file = File::create(path).expect("Failed to create file");
+        serde_json::to_writer_pretty(file, &manifest).expect("Failed to write to file");
+    }
+}
+
+pub(crate) fn format_region(chrom: Option<&str>, start: u32, stop: u32) -> String {
+    match chrom {
+        None => "all".to_string(),
+        Some(chrom) if start == 0 && stop == u32::MAX => chrom.to_string(),
+        Some(chrom) if stop == u32::MAX => format!("{}:{}", chrom, start + 1),
+        Some(chrom) => format!("{}:{}-{}", chrom, start + 1, stop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DepthSettings;
+
+    #[test]
+    fn test_format_region() {
+        assert_eq!(format_region(None, 0, u32::MAX), "all");
+        assert_eq!(format_region(Some("chr1"), 0, u32::MAX), "chr1");
+        assert_eq!(format_region(Some("chr1"), 99, u32::MAX), "chr1:100");
+        assert_eq!(format_region(Some("chr1"), 99, 200), "chr1:100-200");
+    }
+
+    #[test]
+    fn test_header_lines() {
+        let provenance = Provenance::new(
+            vec!["sample.bam".to_string()],
+            Some("chr1"),
+            0,
+            10,
+            Some(RunSettings::Scan(Box::default())),
+        );
+        let lines = provenance.header_lines();
+        assert_eq!(
+            lines[0],
+            format!("ambigviz_version={}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(lines[2], "input=sample.bam");
+        assert_eq!(lines[3], "region=chr1:1-10");
+        assert!(lines[4].starts_with("settings={\"threshold\":0.1"));
+        assert!(lines[4].contains("\"reference\":null"));
+        assert!(lines[4].contains("\"mask_mode\":\"exclude\""));
+
+        let provenance = Provenance::new(
+            vec!["sample.bam".to_string()],
+            Some("chr1"),
+            0,
+            10,
+            Some(RunSettings::Depth(DepthSettings::default())),
+        );
+        assert_eq!(
+            provenance.header_lines()[4],
+            "settings={\"max_depth\":8000,\"dedup_overlaps\":false}"
+        );
+        assert!(provenance
+            .subtitle()
+            .ends_with("| max-depth=8000 dedup-overlaps=false"));
+    }
+}
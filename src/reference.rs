@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::raw::c_char;
+
+use rust_htslib::{bam, htslib};
+use serde::Serialize;
+
+// bases either side of a position in the reported k-mer
+const KMER_FLANK: usize = 5;
+// bases either side of a position used for the GC content
+const GC_FLANK: usize = 25;
+// sam_prob_realn flags to apply extended BAQ to the qualities, as samtools mpileup does
+const BAQ_FLAGS: i32 = 3;
+
+/// Reference sequences read from a FASTA file, held in memory
+pub struct Reference {
+    contigs: BTreeMap<String, Vec<u8>>,
+}
+
+/// The reference sequence around a position
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SequenceContext {
+    // reference base at the position
+    pub base: char,
+    // longest run of a single base at the position or the base after it, as aligners place
+    // insertions before the homopolymer they extend
+    pub homopolymer: u32,
+    // the position and KMER_FLANK bases either side, cut short at contig ends
+    pub kmer: String,
+    // proportion of G or C in the GC_FLANK bases either side of the position
+    pub gc: f64,
+}
+
+impl Reference {
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read reference file {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|e| format!("Invalid reference file {}: {}", path, e))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut contigs = BTreeMap::new();
+        let mut name: Option<String> = None;
+        let mut sequence = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim_end();
+            if let Some(header) = line.strip_prefix('>') {
+                if let Some(name) = name.take() {
+                    contigs.insert(name, std::mem::take(&mut sequence));
+                }
+                // the name ends at the first whitespace, like samtools faidx
+                let header = header.split_whitespace().next().unwrap_or_default();
+                name = Some(header.to_string());
+            } else if !line.is_empty() {
+                if name.is_none() {
+                    return Err(format!(
+                        "line {} comes before the first > header",
+                        index + 1
+                    ));
+                }
+                sequence.extend(line.bytes().map(|base| base.to_ascii_uppercase()));
+            }
+        }
+        if let Some(name) = name {
+            contigs.insert(name, sequence);
+        }
+        if contigs.is_empty() {
+            return Err("no sequences found".to_string());
+        }
+        Ok(Self { contigs })
+    }
+
+    /// The reference base at a 0-based position
+    pub fn base(&self, contig: &str, pos: u32) -> Option<char> {
+        self.contigs
+            .get(contig)?
+            .get(pos as usize)
+            .map(|base| *base as char)
+    }
+
+    /// Base qualities of a read capped by BAQ, the phred scaled probability each base is misaligned.
+    /// None if the contig is not in the reference or htslib cannot realign the read
+    pub fn baq_qualities(&self, record: &bam::Record, contig: &str) -> Option<Vec<u8>> {
+        let sequence = self.contigs.get(contig)?;
+        let mut record = record.clone();
+        // htslib only reads the reference and rewrites the qualities of the copied record
+        let status = unsafe {
+            htslib::sam_prob_realn(
+                record.inner_mut(),
+                sequence.as_ptr() as *const c_char,
+                sequence.len() as htslib::hts_pos_t,
+                BAQ_FLAGS,
+            )
+        };
+        (status >= 0).then(|| record.qual().to_vec())
+    }
+
+    /// The sequence context of a 0-based position, None if the contig or position is not in the reference
+    pub fn context(&self, contig: &str, pos: u32) -> Option<SequenceContext> {
+        let sequence = self.contigs.get(contig)?;
+        let pos = pos as usize;
+        if pos >= sequence.len() {
+            return None;
+        }
+        let homopolymer = homopolymer_length(sequence, pos).max(if pos + 1 < sequence.len() {
+            homopolymer_length(sequence, pos + 1)
+        } else {
+            0
+        });
+        let window = |flank: usize| {
+            &sequence[pos.saturating_sub(flank)..(pos + flank + 1).min(sequence.len())]
+        };
+        let kmer = String::from_utf8_lossy(window(KMER_FLANK)).into_owned();
+        let gc_window = window(GC_FLANK);
+        let gc = gc_window
+            .iter()
+            .filter(|base| matches!(base, b'G' | b'C'))
+            .count() as f64
+            / gc_window.len() as f64;
+        Some(SequenceContext {
+            base: sequence[pos] as char,
+            homopolymer,
+            kmer,
+            gc: (gc * 10000.0).round() / 10000.0,
+        })
+    }
+}
+
+/// Length of the run of identical bases containing `pos`
+fn homopolymer_length(sequence: &[u8], pos: usize) -> u32 {
+    let base = sequence[pos];
+    let before = sequence[..pos]
+        .iter()
+        .rev()
+        .take_while(|other| **other == base)
+        .count();
+    let after = sequence[pos + 1..]
+        .iter()
+        .take_while(|other| **other == base)
+        .count();
+    (before + after + 1) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reference() {
+        let reference = Reference::parse(">chr1 test contig\nacgt\nAC\n\n>chr2\nGG\n").unwrap();
+        assert_eq!(reference.contigs["chr1"], b"ACGTAC");
+        assert_eq!(reference.contigs["chr2"], b"GG");
+        assert_eq!(reference.contigs.len(), 2);
+        assert!(Reference::parse("ACGT\n").is_err());
+        assert!(Reference::parse("").is_err());
+    }
+
+    #[test]
+    fn test_context() {
+        let reference = Reference::parse(">chr1\nCGTAAAAAGCT\n").unwrap();
+        let context = reference.context("chr1", 5).unwrap();
+        assert_eq!(context.base, 'A');
+        assert_eq!(context.homopolymer, 5);
+        assert_eq!(context.kmer, "CGTAAAAAGCT");
+        assert_eq!(context.gc, 0.3636);
+        // the base before a homopolymer takes its length, an insertion there extends it
+        assert_eq!(reference.context("chr1", 2).unwrap().homopolymer, 5);
+        assert_eq!(reference.context("chr1", 0).unwrap().kmer, "CGTAAA");
+        assert_eq!(reference.context("chr1", 9).unwrap().homopolymer, 1);
+        assert_eq!(reference.context("chr1", 11), None);
+    }
+
+    #[test]
+    fn test_baq_qualities() {
+        let reference =
+            Reference::parse(">chr1\nACGTTGCAAGTCCGATAGCTTACGGATCCAGTACGATGCA\n").unwrap();
+        let mut header = bam::Header::new();
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", "chr1")
+                .push_tag(b"LN", 40),
+        );
+        let header = bam::HeaderView::from_header(&header);
+        let record = |sam: &[u8]| bam::Record::from_sam(&header, sam).unwrap();
+        // a read matching the reference keeps its qualities away from its ends
+        let matching = record(
+            b"read1\t0\tchr1\t5\t60\t20M\t*\t0\t0\tTGCAAGTCCGATAGCTTACG\tIIIIIIIIIIIIIIIIIIII",
+        );
+        let qualities = reference.baq_qualities(&matching, "chr1").unwrap();
+        assert_eq!(qualities.len(), 20);
+        assert!(qualities[1..19].iter().all(|quality| *quality == 40));
+        // the last three bases fit the reference better with a deletion before them
+        let misaligned = record(
+            b"read2\t0\tchr1\t5\t60\t20M\t*\t0\t0\tTGCAAGTCCGATAGCTTCGA\tIIIIIIIIIIIIIIIIIIII",
+        );
+        let qualities = reference.baq_qualities(&misaligned, "chr1").unwrap();
+        assert_eq!(qualities[17..], [0, 0, 0]);
+        assert_eq!(qualities[10], 40);
+        assert!(reference.baq_qualities(&matching, "chr2").is_none());
+    }
+}
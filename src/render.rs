@@ -0,0 +1,421 @@
+// Static image rendering with plotters, used in place of plotly and kaleido when the
+// `native-plot` feature is enabled so PNGs can be written without an external executable
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use plotters::prelude::*;
+use plotters::style::register_font;
+
+use crate::style::{hex_rgb, LegendPosition, PlotStyle};
+
+const WIDTH: u32 = 2000;
+const HEIGHT: u32 = 1000;
+const FONT: &str = "sans-serif";
+// fonts tried first when searching the system, in order of preference
+const PREFERRED_FONTS: [&str; 4] = [
+    "DejaVuSans.ttf",
+    "LiberationSans-Regular.ttf",
+    "Arial.ttf",
+    "Helvetica.ttf",
+];
+const FONT_DIRS: [&str; 4] = [
+    "/usr/share/fonts",
+    "/usr/local/share/fonts",
+    "/System/Library/Fonts",
+    "C:\\Windows\\Fonts",
+];
+
+// used when AMBIGVIZ_FONT is unset and no system font is found
+const BUNDLED_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans.ttf");
+
+static REGISTER_FONT: Once = Once::new();
+
+/// One stacked bar series, labels are left out when empty
+pub struct BarSeries {
+    pub name: String,
+    pub colour: String,
+    pub values: Vec<f64>,
+    pub labels: Vec<String>,
+}
+
+/// A position along the bar chart, with its depth and whether it is marked as multi-allelic
+pub struct BarPosition {
+    pub pos: u32,
+    pub depth: u32,
+    pub multiallelic: bool,
+}
+
+/// Text drawn around the chart: the title, the provenance subtitle and the command line footer
+pub struct Titles<'a> {
+    pub title: &'a str,
+    pub subtitle: &'a str,
+    pub footer: &'a str,
+}
+
+/// Stacked bars at evenly spaced positions, with the depth marked against a right hand axis and
+/// a cross over each multi-allelic position
+pub fn bar_chart(
+    path: &str,
+    titles: &Titles,
+    positions: &[BarPosition],
+    series: &[BarSeries],
+    y_label: &str,
+    style: &PlotStyle,
+) {
+    register_system_font();
+    let font_size = style.font_size.unwrap_or(16) as i32;
+    let root = BitMapBackend::new(path, (WIDTH, HEIGHT)).into_drawing_area();
+    root.fill(&WHITE).expect("Failed to draw plot");
+    let area = draw_titles(&root, titles, font_size);
+
+    let y_max = (0..positions.len())
+        .map(|index| series.iter().map(|bars| bars.values[index]).sum::<f64>())
+        .fold(0.0, f64::max)
+        .max(f64::MIN_POSITIVE);
+    let depth_max = positions
+        .iter()
+        .map(|position| position.depth)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let x_range = -0.5..(positions.len().max(1) as f64 - 0.5);
+    let mut chart = ChartBuilder::on(&area)
+        .margin(20)
+        .x_label_area_size(font_size * 3)
+        .y_label_area_size(font_size * 5)
+        .right_y_label_area_size(font_size * 5)
+        .build_cartesian_2d(x_range.clone(), 0.0..y_max * 1.1)
+        .expect("Failed to draw plot")
+        .set_secondary_coord(x_range, 0.0..depth_max as f64 * 1.05);
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .x_desc("Position")
+        .y_desc(y_label)
+        .x_labels(positions.len().clamp(1, 50))
+        .x_label_formatter(&|x| {
+            let index = x.round();
+            if (x - index).abs() > 0.01 || index < 0.0 {
+                return String::new();
+            }
+            positions
+                .get(index as usize)
+                .map(|position| position.pos.to_string())
+                .unwrap_or_default()
+        })
+        .label_style((FONT, font_size))
+        .axis_desc_style((FONT, font_size))
+        .draw()
+        .expect("Failed to draw plot");
+    chart
+        .configure_secondary_axes()
+        .y_desc("Depth")
+        .label_style((FONT, font_size))
+        .axis_desc_style((FONT, font_size))
+        .draw()
+        .expect("Failed to draw plot");
+
+    let mut base = vec![0.0; positions.len()];
+    for bars in series {
+        let colour = parse_colour(&bars.colour);
+        let rectangles: Vec<Rectangle<(f64, f64)>> = bars
+            .values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let x = index as f64;
+                Rectangle::new(
+                    [(x - 0.4, base[index]), (x + 0.4, base[index] + value)],
+                    colour.filled(),
+                )
+            })
+            .collect();
+        chart
+            .draw_series(rectangles)
+            .expect("Failed to draw plot")
+            .label(bars.name.as_str())
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 10, y + 5)], colour.filled()));
+        for (index, label) in bars.labels.iter().enumerate() {
+            let value = bars.values[index];
+            if label.is_empty() || value <= 0.0 {
+                continue;
+            }
+            chart
+                .draw_series(std::iter::once(Text::new(
+                    label.clone(),
+                    (index as f64, base[index] + value / 2.0),
+                    (FONT, font_size).into_font().color(&WHITE),
+                )))
+                .expect("Failed to draw plot");
+        }
+        for (index, value) in bars.values.iter().enumerate() {
+            base[index] += value;
+        }
+    }
+
+    if positions.iter().any(|position| position.multiallelic) {
+        chart
+            .draw_series(
+                positions
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, position)| position.multiallelic)
+                    .map(|(index, _)| {
+                        Cross::new(
+                            (index as f64, base[index] + y_max * 0.03),
+                            8,
+                            BLACK.stroke_width(2),
+                        )
+                    }),
+            )
+            .expect("Failed to draw plot")
+            .label("Multi-allelic")
+            .legend(|(x, y)| Cross::new((x + 5, y), 5, BLACK.stroke_width(2)));
+    }
+
+    let depth_colour = parse_colour(&style.colour("depth"));
+    chart
+        .draw_secondary_series(positions.iter().enumerate().map(|(index, position)| {
+            TriangleMarker::new(
+                (index as f64, position.depth as f64),
+                8,
+                depth_colour.filled(),
+            )
+        }))
+        .expect("Failed to draw plot")
+        .label("Depth")
+        .legend(move |(x, y)| TriangleMarker::new((x + 5, y), 5, depth_colour.filled()));
+    draw_legend(&mut chart, style, font_size);
+    root.present().expect("Failed to write plot");
+}
+
+/// Depth as a single line along the region
+pub fn line_chart(path: &str, titles: &Titles, x: &[u32], y: &[u32], style: &PlotStyle) {
+    register_system_font();
+    let font_size = style.font_size.unwrap_or(16) as i32;
+    let root = BitMapBackend::new(path, (WIDTH, HEIGHT)).into_drawing_area();
+    root.fill(&WHITE).expect("Failed to draw plot");
+    let area = draw_titles(&root, titles, font_size);
+
+    let x_min = x.first().cloned().unwrap_or(0);
+    let x_max = x.last().cloned().unwrap_or(0).max(x_min + 1);
+    let y_max = y.iter().cloned().max().unwrap_or(0).max(1);
+    let mut chart = ChartBuilder::on(&area)
+        .margin(20)
+        .x_label_area_size(font_size * 3)
+        .y_label_area_size(font_size * 5)
+        .build_cartesian_2d(x_min..x_max, 0..y_max + y_max / 20)
+        .expect("Failed to draw plot");
+    chart
+        .configure_mesh()
+        .x_desc("Position")
+        .y_desc("Depth")
+        .label_style((FONT, font_size))
+        .axis_desc_style((FONT, font_size))
+        .draw()
+        .expect("Failed to draw plot");
+    let colour = parse_colour(&style.colour("depth"));
+    chart
+        .draw_series(LineSeries::new(
+            x.iter().cloned().zip(y.iter().cloned()),
+            colour.stroke_width(2),
+        ))
+        .expect("Failed to draw plot")
+        .label("Depth")
+        .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 10, y)], colour));
+    draw_legend(&mut chart, style, font_size);
+    root.present().expect("Failed to write plot");
+}
+
+/// Draw the title, subtitle and footer, returning the area left for the chart
+fn draw_titles<'a>(
+    root: &DrawingArea<BitMapBackend<'a>, plotters::coord::Shift>,
+    titles: &Titles,
+    font_size: i32,
+) -> DrawingArea<BitMapBackend<'a>, plotters::coord::Shift> {
+    let area = root
+        .titled(titles.title, (FONT, font_size * 2))
+        .expect("Failed to draw plot");
+    let area = area
+        .titled(titles.subtitle, (FONT, font_size))
+        .expect("Failed to draw plot");
+    let (area, footer) = area.split_vertically(area.dim_in_pixel().1 - font_size as u32 * 2);
+    footer
+        .draw_text(
+            titles.footer,
+            &(FONT, (font_size * 3 / 4).max(8)).into_text_style(&footer),
+            (20, 0),
+        )
+        .expect("Failed to draw plot");
+    area
+}
+
+fn draw_legend<'a, X, Y>(
+    chart: &mut ChartContext<'a, BitMapBackend<'a>, Cartesian2d<X, Y>>,
+    style: &PlotStyle,
+    font_size: i32,
+) where
+    X: Ranged,
+    Y: Ranged,
+{
+    let position = match style.legend {
+        LegendPosition::Right => SeriesLabelPosition::UpperRight,
+        LegendPosition::Top => SeriesLabelPosition::UpperMiddle,
+        LegendPosition::Bottom => SeriesLabelPosition::LowerMiddle,
+        LegendPosition::None => return,
+    };
+    chart
+        .configure_series_labels()
+        .position(position)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .label_font((FONT, font_size))
+        .draw()
+        .expect("Failed to draw plot");
+}
+
+/// Parse a `#RRGGBB` colour, plotters has no named colours. Colour files are checked for
+/// other colours when they are read
+fn parse_colour(colour: &str) -> RGBColor {
+    let (r, g, b) = hex_rgb(colour).expect("Colours are validated when the colour file is read");
+    RGBColor(r, g, b)
+}
+
+/// Register a TrueType font for text, from AMBIGVIZ_FONT or the system font directories,
+/// falling back to the bundled DejaVu Sans
+fn register_system_font() {
+    REGISTER_FONT.call_once(|| {
+        let path = match env::var("AMBIGVIZ_FONT") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => match find_system_font() {
+                Some(path) => path,
+                None => {
+                    register_font(FONT, FontStyle::Normal, BUNDLED_FONT)
+                        .unwrap_or_else(|_| panic!("Invalid bundled font"));
+                    return;
+                }
+            },
+        };
+        let bytes = fs::read(&path)
+            .unwrap_or_else(|e| panic!("Failed to read font {}: {}", path.display(), e));
+        // plotters keeps a reference to the font for the life of the program
+        let bytes: &'static [u8] = Box::leak(bytes.into_boxed_slice());
+        register_font(FONT, FontStyle::Normal, bytes)
+            .unwrap_or_else(|_| panic!("Invalid font {}", path.display()));
+    });
+}
+
+fn find_system_font() -> Option<PathBuf> {
+    let mut fonts = Vec::new();
+    for dir in FONT_DIRS {
+        collect_fonts(Path::new(dir), &mut fonts);
+    }
+    PREFERRED_FONTS
+        .iter()
+        .find_map(|name| {
+            fonts
+                .iter()
+                .find(|font| font.file_name().is_some_and(|file| file == *name))
+        })
+        .or_else(|| fonts.first())
+        .cloned()
+}
+
+fn collect_fonts(dir: &Path, fonts: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_fonts(&path, fonts);
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("ttf"))
+        {
+            fonts.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_colour() {
+        assert_eq!(parse_colour("#60935D"), RGBColor(0x60, 0x93, 0x5D));
+        assert!(std::panic::catch_unwind(|| parse_colour("grey")).is_err());
+    }
+
+    #[test]
+    fn test_bundled_font() {
+        assert!(register_font("bundled", FontStyle::Normal, BUNDLED_FONT).is_ok());
+    }
+
+    #[test]
+    fn test_bar_chart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bar.png");
+        let titles = Titles {
+            title: "Ambiguous Bases",
+            subtitle: "ambigviz",
+            footer: "ambigviz ambig test.bam",
+        };
+        let series = [
+            BarSeries {
+                name: "A".to_string(),
+                colour: "#60935D".to_string(),
+                values: vec![0.6, 0.8],
+                labels: vec!["0.6".to_string(), String::new()],
+            },
+            BarSeries {
+                name: "G".to_string(),
+                colour: "#F5BB00".to_string(),
+                values: vec![0.4, 0.2],
+                labels: vec!["0.4".to_string(), String::new()],
+            },
+        ];
+        bar_chart(
+            path.to_str().unwrap(),
+            &titles,
+            &[
+                BarPosition {
+                    pos: 1,
+                    depth: 12,
+                    multiallelic: true,
+                },
+                BarPosition {
+                    pos: 5,
+                    depth: 10,
+                    multiallelic: false,
+                },
+            ],
+            &series,
+            "Proportion",
+            &PlotStyle::default(),
+        );
+        assert!(fs::read(&path).unwrap().starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_line_chart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("line.png");
+        let titles = Titles {
+            title: "Depth",
+            subtitle: "ambigviz",
+            footer: "ambigviz depth test.bam",
+        };
+        line_chart(
+            path.to_str().unwrap(),
+            &titles,
+            &[1, 2, 3],
+            &[10, 12, 8],
+            &PlotStyle::default(),
+        );
+        assert!(fs::read(&path).unwrap().starts_with(b"\x89PNG"));
+    }
+}
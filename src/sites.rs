@@ -0,0 +1,181 @@
+use crate::commands::utils::{parse_coordinate, read_text, uncompressed_name};
+
+/// A position to genotype whether or not it is ambiguous
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub contig: String,
+    // 1-based position of the allele, the first deleted base for a deletion
+    pub pos: u32,
+    // VCF ID or the name column of a TSV, "." if missing
+    pub id: String,
+    // VCF REF allele, None for TSV sites
+    pub reference: Option<String>,
+    // allele to report the frequency of, as a base or - for a deletion and + for an insertion
+    pub alt: Option<char>,
+}
+
+/// Read the sites of a VCF file, one per ALT allele, or of a TSV file of contig, position and
+/// optional allele and name columns. Either may be gzip or bgzip compressed
+pub fn read_sites(path: &str) -> Result<Vec<Site>, String> {
+    let contents =
+        read_text(path).map_err(|e| format!("Failed to read sites file {}: {}", path, e))?;
+    let sites = if uncompressed_name(path).ends_with(".vcf") {
+        parse_vcf(&contents)
+    } else {
+        parse_tsv(&contents)
+    };
+    sites.map_err(|e| format!("Invalid sites file {}: {}", path, e))
+}
+
+fn parse_vcf(contents: &str) -> Result<Vec<Site>, String> {
+    let mut sites = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 5 {
+            return Err(format!("line {} has fewer than 5 columns", index + 1));
+        }
+        let pos = parse_position(fields[1], index)?;
+        let reference = fields[3].to_uppercase();
+        for alt in fields[4].split(',') {
+            let alt = alt.to_uppercase();
+            // indels are anchored on the base before them, a deletion is counted at the first
+            // base it removes
+            let (pos, alt) = if alt == "." || alt == "*" {
+                (pos, None)
+            } else if reference.len() > alt.len() {
+                (pos + 1, Some('-'))
+            } else if alt.len() > reference.len() {
+                (pos, Some('+'))
+            } else {
+                (pos, alt.chars().next())
+            };
+            sites.push(Site {
+                contig: fields[0].to_string(),
+                pos,
+                id: fields[2].to_string(),
+                reference: Some(reference.clone()),
+                alt,
+            });
+        }
+    }
+    Ok(sites)
+}
+
+fn parse_tsv(contents: &str) -> Result<Vec<Site>, String> {
+    let mut sites = Vec::new();
+    let mut first = true;
+    for (index, line) in contents.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            return Err(format!("line {} has fewer than 2 columns", index + 1));
+        }
+        // allow a column header on the first line
+        if std::mem::take(&mut first) && fields[1].parse::<u32>().is_err() {
+            continue;
+        }
+        let alt = match fields.get(2).map(|alt| alt.trim().to_uppercase()) {
+            None => None,
+            Some(alt) if alt.is_empty() || alt == "." => None,
+            Some(alt) if matches!(alt.as_str(), "A" | "C" | "G" | "T" | "-" | "+") => {
+                alt.chars().next()
+            }
+            Some(alt) => {
+                return Err(format!(
+                    "line {} has an invalid allele {}, expected one of A, C, G, T, - or +",
+                    index + 1,
+                    alt
+                ))
+            }
+        };
+        sites.push(Site {
+            contig: fields[0].to_string(),
+            pos: parse_position(fields[1], index)?,
+            id: fields
+                .get(3)
+                .filter(|id| !id.is_empty())
+                .unwrap_or(&".")
+                .to_string(),
+            reference: None,
+            alt,
+        });
+    }
+    Ok(sites)
+}
+
+fn parse_position(field: &str, index: usize) -> Result<u32, String> {
+    match parse_coordinate(field, index, "position")? {
+        0 => Err(format!("line {} has an invalid position 0", index + 1)),
+        pos => Ok(pos),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vcf_sites() {
+        let vcf = "##fileformat=VCFv4.2\n\
+                   #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+                   chr1\t5\trs1\tA\tG,T\t.\t.\t.\n\
+                   chr1\t10\t.\tAC\tA\t.\t.\t.\n\
+                   chr1\t20\t.\tA\tAT\t.\t.\t.\n";
+        let sites = parse_vcf(vcf).unwrap();
+        let alleles: Vec<(u32, Option<char>)> =
+            sites.iter().map(|site| (site.pos, site.alt)).collect();
+        assert_eq!(
+            alleles,
+            vec![
+                (5, Some('G')),
+                (5, Some('T')),
+                (11, Some('-')),
+                (20, Some('+'))
+            ]
+        );
+        assert_eq!(sites[0].id, "rs1");
+        assert_eq!(sites[2].reference.as_deref(), Some("AC"));
+    }
+
+    #[test]
+    fn test_parse_tsv_sites() {
+        let tsv = "contig\tpos\talt\tname\nchr1\t5\tg\tK417N\nchr1\t9\nchr2\t3\t.\n";
+        let sites = parse_tsv(tsv).unwrap();
+        assert_eq!(
+            sites[0],
+            Site {
+                contig: "chr1".to_string(),
+                pos: 5,
+                id: "K417N".to_string(),
+                reference: None,
+                alt: Some('G'),
+            }
+        );
+        assert_eq!((sites[1].pos, sites[1].alt), (9, None));
+        assert_eq!(sites[2].id, ".");
+        assert!(parse_tsv("chr1\t5\tAT\n").is_err());
+        assert!(parse_tsv("chr1\t0\n").is_err());
+    }
+
+    #[test]
+    fn test_read_compressed_vcf_sites() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sites.vcf.gz");
+        let mut writer = rust_htslib::bgzf::Writer::from_path(&path).unwrap();
+        std::io::Write::write_all(
+            &mut writer,
+            b"#CHROM\tPOS\tID\tREF\tALT\nchr1\t5\trs1\tA\tG\n",
+        )
+        .unwrap();
+        drop(writer);
+        let sites = read_sites(path.to_str().unwrap()).unwrap();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].reference, Some("A".to_string()));
+        assert_eq!((sites[0].pos, sites[0].alt), (5, Some('G')));
+    }
+}
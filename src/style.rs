@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use clap::ValueEnum;
+#[cfg(feature = "plot")]
+use plotly::common::{Anchor, Font, Orientation};
+#[cfg(feature = "plot")]
+use plotly::layout::Legend;
+#[cfg(feature = "plot")]
+use plotly::Layout;
+use serde::{Deserialize, Serialize};
+
+const COLOUR_KEYS: [&str; 7] = ["A", "C", "G", "T", "-", "+", "depth"];
+
+/// Built in colour sets for alleles and the depth line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    /// The original ambigviz colours
+    Default,
+    /// Okabe-Ito colours, distinguishable with common forms of colour blindness
+    Colourblind,
+    /// Shades of grey for black and white printing
+    Greyscale,
+}
+
+impl Palette {
+    fn colour(&self, key: &str) -> &'static str {
+        let colours = match self {
+            Palette::Default => [
+                "#60935D", "#1B5299", "#F5BB00", "#E63946", "#000000", "#6A041D", "#1F77B4",
+            ],
+            Palette::Colourblind => [
+                "#009E73", "#0072B2", "#E69F00", "#D55E00", "#000000", "#CC79A7", "#56B4E9",
+            ],
+            Palette::Greyscale => [
+                "#4D4D4D", "#808080", "#B3B3B3", "#1A1A1A", "#000000", "#D9D9D9", "#333333",
+            ],
+        };
+        let index = COLOUR_KEYS
+            .iter()
+            .position(|colour_key| *colour_key == key)
+            .expect("Invalid colour key");
+        colours[index]
+    }
+}
+
+/// Where the plot legend is drawn
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LegendPosition {
+    Right,
+    Top,
+    Bottom,
+    None,
+}
+
+/// Presentation options shared by the ambig and depth plots
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlotStyle {
+    // title template, see PlotStyle::title for the placeholders
+    pub title: Option<String>,
+    pub palette: Palette,
+    // colours read from a colour file, used in place of the palette
+    pub colours: BTreeMap<String, String>,
+    pub font_size: Option<usize>,
+    pub legend: LegendPosition,
+}
+
+impl Default for PlotStyle {
+    fn default() -> Self {
+        Self {
+            title: None,
+            palette: Palette::Default,
+            colours: BTreeMap::new(),
+            font_size: None,
+            legend: LegendPosition::Right,
+        }
+    }
+}
+
+impl PlotStyle {
+    /// Colour of an allele (`A`, `C`, `G`, `T`, `-`, `+`) or of the depth line (`depth`)
+    pub fn colour(&self, key: &str) -> String {
+        match self.colours.get(key) {
+            Some(colour) => colour.clone(),
+            None => self.palette.colour(key).to_string(),
+        }
+    }
+
+    /// The plot title, either the default name of the plot or the title template with
+    /// `{plot}`, `{sample}`, `{contig}` and `{region}` filled in
+    pub fn title(&self, plot: &str, sample: &str, contig: &str, region: &str) -> String {
+        match &self.title {
+            Some(template) => template
+                .replace("{plot}", plot)
+                .replace("{sample}", sample)
+                .replace("{contig}", contig)
+                .replace("{region}", region),
+            None => plot.to_string(),
+        }
+    }
+
+    /// Apply the font size and legend placement to a layout
+    #[cfg(feature = "plot")]
+    pub fn apply(&self, mut layout: Layout) -> Layout {
+        if let Some(font_size) = self.font_size {
+            layout = layout.font(Font::new().size(font_size));
+        }
+        match self.legend {
+            LegendPosition::Right => layout,
+            LegendPosition::Top => layout.legend(
+                Legend::new()
+                    .orientation(Orientation::Horizontal)
+                    .x(0.0)
+                    .y(1.02)
+                    .y_anchor(Anchor::Bottom),
+            ),
+            // below the command line annotation
+            LegendPosition::Bottom => layout.legend(
+                Legend::new()
+                    .orientation(Orientation::Horizontal)
+                    .x(0.0)
+                    .y(-0.15)
+                    .y_anchor(Anchor::Top),
+            ),
+            LegendPosition::None => layout.show_legend(false),
+        }
+    }
+}
+
+/// Read allele and depth colours from a TOML file of `key = "colour"` pairs
+pub fn read_colours(path: &str) -> Result<BTreeMap<String, String>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read colour file {}: {}", path, e))?;
+    parse_colours(&contents).map_err(|e| format!("Invalid colour file {}: {}", path, e))
+}
+
+fn parse_colours(contents: &str) -> Result<BTreeMap<String, String>, String> {
+    let colours: BTreeMap<String, String> = toml::from_str(contents).map_err(|e| e.to_string())?;
+    for key in colours.keys() {
+        if !COLOUR_KEYS.contains(&key.as_str()) {
+            return Err(format!(
+                "Unknown colour key {}, expected one of {}",
+                key,
+                COLOUR_KEYS.join(", ")
+            ));
+        }
+    }
+    #[cfg(feature = "native-plot")]
+    for (key, colour) in &colours {
+        if hex_rgb(colour).is_none() {
+            return Err(format!(
+                "The native renderer only supports #RRGGBB colours, found {} for {}",
+                colour, key
+            ));
+        }
+    }
+    Ok(colours)
+}
+
+/// Red, green and blue channels of a `#RRGGBB` colour
+#[cfg(feature = "native-plot")]
+pub fn hex_rgb(colour: &str) -> Option<(u8, u8, u8)> {
+    let hex = colour.strip_prefix('#')?;
+    let channel = |index: usize| {
+        hex.get(index..index + 2)
+            .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Some((r, g, b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colours() {
+        let colours = parse_colours("A = \"#FF0000\"\n\"-\" = \"#808080\"\n").unwrap();
+        let style = PlotStyle {
+            palette: Palette::Colourblind,
+            colours,
+            ..PlotStyle::default()
+        };
+        assert_eq!(style.colour("A"), "#FF0000");
+        assert_eq!(style.colour("-"), "#808080");
+        assert_eq!(style.colour("C"), "#0072B2");
+        assert!(parse_colours("N = \"#FF0000\"\n").is_err());
+        // plotly accepts colour names, the native renderer does not
+        assert_eq!(
+            parse_colours("depth = \"grey\"\n").is_ok(),
+            cfg!(not(feature = "native-plot"))
+        );
+    }
+
+    #[test]
+    fn test_title() {
+        let style = PlotStyle {
+            title: Some("{sample} {region} - {plot}".to_string()),
+            ..PlotStyle::default()
+        };
+        assert_eq!(
+            style.title("Depth", "sample1", "chr1", "chr1:1-100"),
+            "sample1 chr1:1-100 - Depth"
+        );
+        assert_eq!(
+            PlotStyle::default().title("Depth", "sample1", "chr1", "chr1:1-100"),
+            "Depth"
+        );
+    }
+}